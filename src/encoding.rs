@@ -0,0 +1,130 @@
+use std::{borrow::Cow, str};
+
+use crate::{
+    error::{Position, XmlError, XmlErrorKind},
+    parse::xml_declaration,
+};
+
+/// The encodings that `decode` can turn into UTF-8.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    /// ISO-8859-1, also used for `US-ASCII`.
+    Latin1,
+    Windows1252,
+}
+
+impl Encoding {
+    /// Looks up an `encoding=` name of the XML declaration, ignoring case.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "utf-8" | "utf8" => Some(Encoding::Utf8),
+            "utf-16le" => Some(Encoding::Utf16Le),
+            "utf-16be" => Some(Encoding::Utf16Be),
+            "iso-8859-1" | "iso8859-1" | "iso_8859-1" | "latin1" | "l1" | "us-ascii" | "ascii" => {
+                Some(Encoding::Latin1)
+            }
+            "windows-1252" | "cp1252" => Some(Encoding::Windows1252),
+            _ => None,
+        }
+    }
+
+    /// Finds the encoding of `bytes` from its byte order mark, or else from how `<?xml`
+    /// is encoded and the `encoding=` of the declaration. Also returns the length of
+    /// the byte order mark.
+    ///
+    /// Defaults to UTF-8, as the spec does.
+    pub fn detect(bytes: &[u8]) -> Result<(Self, usize), XmlError> {
+        match bytes {
+            [0xEF, 0xBB, 0xBF, ..] => return Ok((Encoding::Utf8, 3)),
+            [0xFF, 0xFE, ..] => return Ok((Encoding::Utf16Le, 2)),
+            [0xFE, 0xFF, ..] => return Ok((Encoding::Utf16Be, 2)),
+            [b'<', 0, b'?', 0, ..] => return Ok((Encoding::Utf16Le, 0)),
+            [0, b'<', 0, b'?', ..] => return Ok((Encoding::Utf16Be, 0)),
+            _ => (),
+        }
+
+        // The declaration is ASCII whatever the encoding, as long as it is compatible with
+        // ASCII, which is all that is left. A malformed one is left to the parser.
+        let declaration = bytes
+            .windows(2)
+            .position(|w| w == b"?>")
+            .and_then(|end| str::from_utf8(&bytes[..end + 2]).ok())
+            .and_then(|d| xml_declaration::<()>(d).ok());
+        let Some((_, declaration)) = declaration else {
+            return Ok((Encoding::Utf8, 0));
+        };
+
+        match declaration.encoding {
+            None => Ok((Encoding::Utf8, 0)),
+            Some(name) => match Encoding::from_name(&name) {
+                Some(Encoding::Utf16Le | Encoding::Utf16Be) | None => {
+                    Err(decoding_error("", format!("unsupported encoding `{name}`")))
+                }
+                Some(encoding) => Ok((encoding, 0)),
+            },
+        }
+    }
+}
+
+fn decoding_error(decoded: &str, message: String) -> XmlError {
+    XmlError::new(
+        decoded,
+        Position::of(decoded, ""),
+        XmlErrorKind::Encoding(message),
+    )
+}
+
+// NOTE:
+// Windows-1252 only differs from ISO-8859-1 on 0x80..=0x9F. The five bytes it leaves
+// undefined are mapped to the matching C1 control, like browsers do.
+const WINDOWS_1252: [char; 32] = [
+    '€', '\u{81}', '‚', 'ƒ', '„', '…', '†', '‡', 'ˆ', '‰', 'Š', '‹', 'Œ', '\u{8D}', 'Ž', '\u{8F}',
+    '\u{90}', '‘', '’', '“', '”', '•', '–', '—', '˜', '™', 'š', '›', 'œ', '\u{9D}', 'ž', 'Ÿ',
+];
+
+/// Decodes `bytes` into UTF-8, in the encoding found by `Encoding::detect`, without its
+/// byte order mark.
+///
+/// Only allocates when the input is not already UTF-8. Errors point at the first
+/// character that could not be decoded.
+pub fn decode(bytes: &[u8]) -> Result<Cow<'_, str>, XmlError> {
+    let (encoding, bom) = Encoding::detect(bytes)?;
+    let bytes = &bytes[bom..];
+
+    match encoding {
+        Encoding::Utf8 => str::from_utf8(bytes).map(Cow::Borrowed).map_err(|e| {
+            let decoded = str::from_utf8(&bytes[..e.valid_up_to()]).unwrap_or_default();
+            decoding_error(decoded, "invalid UTF-8".into())
+        }),
+        Encoding::Utf16Le | Encoding::Utf16Be => {
+            let units = bytes.chunks(2).map(|pair| match (pair, encoding) {
+                ([low, high], Encoding::Utf16Le) => u16::from_le_bytes([*low, *high]),
+                ([high, low], _) => u16::from_be_bytes([*high, *low]),
+                // An odd byte at the end can never be decoded.
+                _ => 0xDC00,
+            });
+
+            let mut decoded = String::with_capacity(bytes.len() / 2);
+            for c in char::decode_utf16(units) {
+                match c {
+                    Ok(c) => decoded.push(c),
+                    Err(_) => return Err(decoding_error(&decoded, "invalid UTF-16".into())),
+                }
+            }
+            Ok(Cow::Owned(decoded))
+        }
+        Encoding::Latin1 => Ok(Cow::Owned(bytes.iter().map(|&b| char::from(b)).collect())),
+        Encoding::Windows1252 => Ok(Cow::Owned(
+            bytes
+                .iter()
+                .map(|&b| match b {
+                    0x80..=0x9F => WINDOWS_1252[usize::from(b - 0x80)],
+                    _ => char::from(b),
+                })
+                .collect(),
+        )),
+    }
+}
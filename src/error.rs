@@ -0,0 +1,446 @@
+use nom::error::{ContextError, ErrorKind, FromExternalError, ParseError};
+use std::{error::Error, fmt};
+
+/// Well-formedness errors reported by the combinators in `parse` through
+/// `FromExternalError`, on top of nom's own `ErrorKind`s.
+///
+/// The slices point into the parsed input so that positions can be recovered.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Malformed<'a> {
+    /// The element whose opening tag starts at `opened` is not closed.
+    Unclosed {
+        opened: &'a str,
+    },
+    /// The element whose opening tag starts at `opened` is closed by the closing tag of
+    /// another element, starting at `closing`.
+    Mismatched {
+        opened: &'a str,
+        closing: &'a str,
+    },
+    /// `<?xml ... ?>` appears somewhere else than at the start of the document.
+    MisplacedDeclaration,
+    /// The reference to the general entity `name` cannot be expanded. `name` may be that
+    /// of an entity referenced from the replacement text of another one.
+    Entity {
+        name: String,
+        error: EntityError,
+    },
+    LimitExceeded(Limit),
+    /// The attribute `name` appears again in the same tag, `name` being the second
+    /// occurrence.
+    DuplicateAttribute {
+        name: &'a str,
+    },
+}
+
+/// Why a reference to a general entity could not be expanded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntityError {
+    Undefined,
+    /// Declared with a `SYSTEM` or `PUBLIC` identifier, which is never fetched.
+    External,
+    /// Its replacement text refers back to it, directly or not.
+    Recursive,
+    /// Its replacement text contains `<`, and only text is supported.
+    Markup,
+}
+
+/// A limit of `ParseOptions` that the input went over, with its configured value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    EntityDepth(usize),
+    EntityExpansion(usize),
+    Depth(usize),
+    AttributesPerElement(usize),
+    NameLength(usize),
+    TextLength(usize),
+    TotalNodes(usize),
+}
+
+impl Limit {
+    pub fn max(self) -> usize {
+        match self {
+            Limit::EntityDepth(max)
+            | Limit::EntityExpansion(max)
+            | Limit::Depth(max)
+            | Limit::AttributesPerElement(max)
+            | Limit::NameLength(max)
+            | Limit::TextLength(max)
+            | Limit::TotalNodes(max) => max,
+        }
+    }
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::EntityDepth(max) => write!(f, "more than {max} nested entity references"),
+            Limit::EntityExpansion(max) => write!(f, "more than {max} bytes of expanded entities"),
+            Limit::Depth(max) => write!(f, "more than {max} nested elements"),
+            Limit::AttributesPerElement(max) => write!(f, "more than {max} attributes"),
+            Limit::NameLength(max) => write!(f, "a name longer than {max} bytes"),
+            Limit::TextLength(max) => write!(f, "a text longer than {max} bytes"),
+            Limit::TotalNodes(max) => write!(f, "more than {max} nodes"),
+        }
+    }
+}
+
+/// A location in the parsed input. `line` and `column` start at 1, `column`
+/// counts characters rather than bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Position {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Position {
+    pub const START: Position = Position {
+        offset: 0,
+        line: 1,
+        column: 1,
+    };
+
+    /// `rest` must be a suffix of `source`.
+    pub fn of(source: &str, rest: &str) -> Self {
+        Position::START.advanced_by(&source[..source.len() - rest.len()])
+    }
+
+    /// The position right after `text`, if `text` starts at `self`.
+    pub fn advanced_by(self, text: &str) -> Self {
+        let (line, column) = match text.rfind('\n') {
+            Some(n) => (
+                self.line + text.matches('\n').count(),
+                text[n + 1..].chars().count() + 1,
+            ),
+            None => (self.line, self.column + text.chars().count()),
+        };
+
+        Position {
+            offset: self.offset + text.len(),
+            line,
+            column,
+        }
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum XmlErrorKind {
+    /// Human readable description of the construct the parser was looking for.
+    Expected(String),
+    UnclosedElement {
+        name: String,
+        opened_at: Position,
+    },
+    /// The error position is that of the closing tag.
+    MismatchedTag {
+        expected: String,
+        found: String,
+        opened_at: Position,
+    },
+    MisplacedDeclaration,
+    Entity {
+        name: String,
+        error: EntityError,
+    },
+    LimitExceeded(Limit),
+    /// The error position is that of the second occurrence.
+    DuplicateAttribute(String),
+    /// The input bytes are not valid in their encoding, or the encoding is unsupported.
+    Encoding(String),
+    /// Reading the input failed, or it was not valid UTF-8.
+    Io(String),
+}
+
+impl fmt::Display for XmlErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            XmlErrorKind::Expected(what) => write!(f, "expected {what}"),
+            XmlErrorKind::UnclosedElement { name, opened_at } => write!(
+                f,
+                "expected closing tag `</{name}>` for element opened at {opened_at}"
+            ),
+            XmlErrorKind::MismatchedTag {
+                expected,
+                found,
+                opened_at,
+            } => write!(
+                f,
+                "expected closing tag `</{expected}>` for element opened at {opened_at}, found `</{found}>`"
+            ),
+            XmlErrorKind::MisplacedDeclaration => f.write_str(
+                "XML declaration is only allowed at the start of the document",
+            ),
+            XmlErrorKind::Entity { name, error } => match error {
+                EntityError::Undefined => write!(f, "undefined entity `&{name};`"),
+                EntityError::External => write!(f, "external entity `&{name};` cannot be expanded"),
+                EntityError::Recursive => write!(f, "entity `&{name};` refers to itself"),
+                EntityError::Markup => write!(
+                    f,
+                    "entity `&{name};` expands to markup, which is not supported"
+                ),
+            },
+            XmlErrorKind::LimitExceeded(limit) => write!(f, "found {limit}"),
+            XmlErrorKind::DuplicateAttribute(name) => write!(f, "duplicate attribute `{name}`"),
+            XmlErrorKind::Encoding(e) => write!(f, "cannot decode input: {e}"),
+            XmlErrorKind::Io(e) => write!(f, "failed to read input: {e}"),
+        }
+    }
+}
+
+/// Error returned by `Xml::from_input_str` and friends.
+///
+/// `Display` renders the message followed by the offending source line and a caret.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct XmlError {
+    pub position: Position,
+    pub kind: XmlErrorKind,
+    // At most `SOURCE_CONTEXT` characters of the line on either side of the error, so
+    // that a document on a single huge line is not copied whole.
+    source_line: Box<str>,
+    // Characters of `source_line` before the error.
+    caret: usize,
+}
+
+const SOURCE_CONTEXT: usize = 60;
+const ELLIPSIS: &str = "...";
+
+impl XmlError {
+    pub fn new(source: &str, position: Position, kind: XmlErrorKind) -> Self {
+        let (before, after) = source.split_at(position.offset);
+        let start = before
+            .char_indices()
+            .rev()
+            .take_while(|&(_, c)| c != '\n')
+            .take(SOURCE_CONTEXT)
+            .last()
+            .map_or(position.offset, |(n, _)| n);
+        let end = after
+            .char_indices()
+            .take_while(|&(_, c)| c != '\n')
+            .take(SOURCE_CONTEXT)
+            .last()
+            .map_or(position.offset, |(n, c)| position.offset + n + c.len_utf8());
+
+        let mut source_line = String::new();
+        if start > 0 && !source[..start].ends_with('\n') {
+            source_line += ELLIPSIS;
+        }
+        let caret = source_line.len() + source[start..position.offset].chars().count();
+        source_line += source[start..end].trim_end_matches('\r');
+        if end < source.len() && !source[end..].starts_with(['\r', '\n']) {
+            source_line += ELLIPSIS;
+        }
+
+        XmlError {
+            position,
+            kind,
+            source_line: source_line.into(),
+            caret,
+        }
+    }
+
+    /// Like `new`, for when `source` is only the part of the input starting at `base`.
+    pub(crate) fn within(source: &str, rest: &str, base: Position, kind: XmlErrorKind) -> Self {
+        let relative = Position::of(source, rest);
+        let mut e = XmlError::new(source, relative, kind);
+        e.position = base.advanced_by(&source[..relative.offset]);
+        e
+    }
+
+    pub fn offset(&self) -> usize {
+        self.position.offset
+    }
+
+    pub fn line(&self) -> usize {
+        self.position.line
+    }
+
+    pub fn column(&self) -> usize {
+        self.position.column
+    }
+
+    pub(crate) fn from_nom(source: &str, e: nom::Err<ErrorTrace<'_>>) -> Self {
+        XmlError::from_nom_within(source, Position::START, e)
+    }
+
+    pub(crate) fn from_nom_within(
+        source: &str,
+        base: Position,
+        e: nom::Err<ErrorTrace<'_>>,
+    ) -> Self {
+        match e {
+            nom::Err::Error(e) | nom::Err::Failure(e) => e.into_xml_error(source, base),
+            nom::Err::Incomplete(_) => XmlError::within(
+                source,
+                "",
+                base,
+                XmlErrorKind::Expected("more input".into()),
+            ),
+        }
+    }
+}
+
+impl fmt::Display for XmlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let line_number = self.position.line.to_string();
+        let gutter = " ".repeat(line_number.len());
+        // Keep tabs so that the caret lines up with the source line.
+        let indent: String = self
+            .source_line
+            .chars()
+            .take(self.caret)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        writeln!(f, "{} at {}", self.kind, self.position)?;
+        writeln!(f, "{gutter} |")?;
+        writeln!(f, "{line_number} | {}", self.source_line)?;
+        write!(f, "{gutter} | {indent}^")
+    }
+}
+
+impl Error for XmlError {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Cause<'a> {
+    Kind(ErrorKind),
+    Char(char),
+    Malformed(Malformed<'a>),
+}
+
+// Longer than any literal the parsers look for, e.g. `<!NOTATION`.
+const LONGEST_LITERAL: usize = 16;
+
+// NOTE:
+// Unlike `VerboseError` this only keeps the innermost failure and the closest
+// `context` around it, which is what ends up in the message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ErrorTrace<'a> {
+    at: &'a str,
+    cause: Cause<'a>,
+    context: Option<&'static str>,
+}
+
+impl<'a> ErrorTrace<'a> {
+    /// Whether the error may only be due to the input being cut short, so that more
+    /// input could still make it parse.
+    pub(crate) fn is_truncation(&self) -> bool {
+        // NOTE:
+        // `tag` fails at the start of a literal that is cut short, e.g. `<!DOC`, so an
+        // error that close to the end may still be one. Terminators that are not there
+        // yet, like `-->`, make `take_until` fail wherever it started.
+        self.at.len() < LONGEST_LITERAL || matches!(self.cause, Cause::Kind(ErrorKind::TakeUntil))
+    }
+
+    fn into_xml_error(self, source: &str, base: Position) -> XmlError {
+        let position_of = |rest: &str| base.advanced_by(&source[..source.len() - rest.len()]);
+        let kind = match self.cause {
+            Cause::Malformed(Malformed::Unclosed { opened }) => XmlErrorKind::UnclosedElement {
+                name: tag_name(&opened[1..]).into(),
+                opened_at: position_of(opened),
+            },
+            Cause::Malformed(Malformed::Mismatched { opened, closing }) => {
+                XmlErrorKind::MismatchedTag {
+                    expected: tag_name(&opened[1..]).into(),
+                    found: tag_name(&closing[2..]).into(),
+                    opened_at: position_of(opened),
+                }
+            }
+            Cause::Malformed(Malformed::MisplacedDeclaration) => XmlErrorKind::MisplacedDeclaration,
+            Cause::Malformed(Malformed::Entity { name, error }) => {
+                XmlErrorKind::Entity { name, error }
+            }
+            Cause::Malformed(Malformed::LimitExceeded(limit)) => XmlErrorKind::LimitExceeded(limit),
+            Cause::Malformed(Malformed::DuplicateAttribute { name }) => {
+                XmlErrorKind::DuplicateAttribute(name.into())
+            }
+            Cause::Char(c) => XmlErrorKind::Expected(match self.context {
+                Some(context) => format!("`{c}` in {}", describe(context)),
+                None => format!("`{c}`"),
+            }),
+            Cause::Kind(kind) => XmlErrorKind::Expected(match self.context {
+                Some(context) => describe(context),
+                None => describe_kind(kind).into(),
+            }),
+        };
+
+        XmlError::within(source, self.at, base, kind)
+    }
+}
+
+// NOTE:
+// `name` starts right after the `<` or `</` of a tag that already parsed successfully.
+fn tag_name(name: &str) -> &str {
+    let end = name
+        .find(|c: char| c.is_whitespace() || c == '>' || c == '/')
+        .unwrap_or(name.len());
+    &name[..end]
+}
+
+fn describe(context: &str) -> String {
+    context.replace('_', " ")
+}
+
+fn describe_kind(kind: ErrorKind) -> &'static str {
+    match kind {
+        ErrorKind::Eof => "end of input",
+        ErrorKind::Tag => "markup",
+        ErrorKind::TakeUntil => "terminator",
+        _ => "well-formed XML",
+    }
+}
+
+impl<'a> ParseError<&'a str> for ErrorTrace<'a> {
+    fn from_error_kind(at: &'a str, kind: ErrorKind) -> Self {
+        ErrorTrace {
+            at,
+            cause: Cause::Kind(kind),
+            context: None,
+        }
+    }
+
+    fn append(_: &'a str, _: ErrorKind, other: Self) -> Self {
+        other
+    }
+
+    fn from_char(at: &'a str, c: char) -> Self {
+        ErrorTrace {
+            at,
+            cause: Cause::Char(c),
+            context: None,
+        }
+    }
+
+    // Report whichever alternative got further into the input.
+    fn or(self, other: Self) -> Self {
+        if other.at.len() <= self.at.len() {
+            other
+        } else {
+            self
+        }
+    }
+}
+
+impl<'a> ContextError<&'a str> for ErrorTrace<'a> {
+    fn add_context(_: &'a str, context: &'static str, mut other: Self) -> Self {
+        other.context.get_or_insert(context);
+        other
+    }
+}
+
+impl<'a> FromExternalError<&'a str, Malformed<'a>> for ErrorTrace<'a> {
+    fn from_external_error(at: &'a str, _: ErrorKind, m: Malformed<'a>) -> Self {
+        ErrorTrace {
+            at,
+            cause: Cause::Malformed(m),
+            context: None,
+        }
+    }
+}
@@ -1,3 +1,6 @@
+pub mod encoding;
+pub mod error;
+pub mod namespace;
 pub mod parse;
 pub mod serialize;
 pub mod types;
@@ -0,0 +1,137 @@
+use std::{borrow::Cow, error::Error, fmt};
+
+use crate::types::*;
+
+/// Bound to the `xml` prefix without needing a declaration.
+pub const XML_NAMESPACE: &str = "http://www.w3.org/XML/1998/namespace";
+/// Bound to the `xmlns` prefix without needing a declaration.
+pub const XMLNS_NAMESPACE: &str = "http://www.w3.org/2000/xmlns/";
+
+/// An element used a prefix that no `xmlns:prefix` attribute in scope declares.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NamespaceError {
+    pub prefix: String,
+}
+
+impl fmt::Display for NamespaceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "namespace prefix `{}` is not declared", self.prefix)
+    }
+}
+
+impl Error for NamespaceError {}
+
+// NOTE:
+// `scope` holds the declarations of the enclosing elements, innermost last.
+// A `None` prefix is the default namespace (`xmlns="..."`).
+fn lookup<P: AsRef<str>, U: AsRef<str> + Clone>(
+    scope: &[(Option<P>, U)],
+    prefix: Option<&str>,
+) -> Result<Option<U>, NamespaceError> {
+    match scope
+        .iter()
+        .rev()
+        .find(|(p, _)| p.as_ref().map(AsRef::as_ref) == prefix)
+    {
+        // `xmlns=""` takes unprefixed names back out of any namespace.
+        Some((_, uri)) if uri.as_ref().is_empty() => Ok(None),
+        Some((_, uri)) => Ok(Some(uri.clone())),
+        None => match prefix {
+            Some(prefix) => Err(NamespaceError {
+                prefix: prefix.into(),
+            }),
+            None => Ok(None),
+        },
+    }
+}
+
+fn declaration(key: &str) -> Option<Option<&str>> {
+    match key {
+        "xmlns" => Some(None),
+        _ => key.strip_prefix("xmlns:").map(Some),
+    }
+}
+
+// NOTE:
+// Iterative so that documents of any depth can be resolved, see `Drop for Xml`.
+pub(crate) fn resolve(mut x: Xml) -> Result<Xml, NamespaceError> {
+    let mut scope: Vec<(Option<String>, String)> = vec![
+        (Some("xml".into()), XML_NAMESPACE.into()),
+        (Some("xmlns".into()), XMLNS_NAMESPACE.into()),
+    ];
+    // The elements whose children are being resolved, with the length of `scope` outside
+    // of them and the children left to resolve.
+    let mut open: Vec<(usize, std::slice::IterMut<Xml>)> = Vec::new();
+    let mut next = Some(&mut x);
+
+    loop {
+        if let Some(Xml::Element(tag, children)) = next.take() {
+            let outer_scope = scope.len();
+            for (k, v) in &tag.attributes {
+                if let Some(prefix) = declaration(k) {
+                    scope.push((prefix.map(String::from), v.clone()));
+                }
+            }
+
+            tag.value.namespace_uri = lookup(&scope, tag.value.prefix.as_deref())?;
+            match children {
+                Some(children) => open.push((outer_scope, children.iter_mut())),
+                None => scope.truncate(outer_scope),
+            }
+        }
+
+        let Some((outer_scope, children)) = open.last_mut() else {
+            break;
+        };
+        match children.next() {
+            Some(child) => next = Some(child),
+            None => {
+                scope.truncate(*outer_scope);
+                open.pop();
+            }
+        }
+    }
+
+    Ok(x)
+}
+
+// NOTE:
+// See `resolve`.
+pub(crate) fn resolve_ref(mut x: XmlRef) -> Result<XmlRef, NamespaceError> {
+    let mut scope: Vec<(Option<&str>, Cow<str>)> = vec![
+        (Some("xml"), XML_NAMESPACE.into()),
+        (Some("xmlns"), XMLNS_NAMESPACE.into()),
+    ];
+    let mut open: Vec<(usize, std::slice::IterMut<XmlRef>)> = Vec::new();
+    let mut next = Some(&mut x);
+
+    loop {
+        if let Some(XmlRef::Element(tag, children)) = next.take() {
+            let outer_scope = scope.len();
+            for (k, v) in &tag.attributes {
+                if let Some(prefix) = declaration(k) {
+                    scope.push((prefix, v.clone()));
+                }
+            }
+
+            tag.value.namespace_uri = lookup(&scope, tag.value.prefix)?;
+            match children {
+                Some(children) => open.push((outer_scope, children.iter_mut())),
+                None => scope.truncate(outer_scope),
+            }
+        }
+
+        let Some((outer_scope, children)) = open.last_mut() else {
+            break;
+        };
+        match children.next() {
+            Some(child) => next = Some(child),
+            None => {
+                scope.truncate(*outer_scope);
+                open.pop();
+            }
+        }
+    }
+
+    Ok(x)
+}
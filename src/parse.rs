@@ -1,248 +1,1022 @@
 use nom::{
     branch::alt,
-    bytes::complete::{escaped, is_not, tag, take_till1, take_till, take_while, take_while1},
-    character::complete::{char, one_of, multispace0},
-    combinator::{cut, map, opt, value},
-    error::{context, ContextError, ParseError},
-    multi::{many0, many1, separated_list0},
-    sequence::{delimited, preceded, separated_pair, terminated, tuple},
-    IResult, Parser,
+    bytes::complete::{is_not, tag, take_till, take_until, take_while, take_while1},
+    character::complete::{char, one_of, multispace0, multispace1, satisfy},
+    combinator::{all_consuming, cut, eof, flat_map, map, not, opt, peek, recognize, value, verify},
+    error::{context, ContextError, ErrorKind, FromExternalError, ParseError},
+    multi::{many0, many0_count, many1, many1_count},
+    sequence::{delimited, pair, preceded, separated_pair, terminated, tuple},
+    IResult, Offset, Parser,
 };
+use std::borrow::Cow;
+use std::cell::Cell;
 use std::str;
-use std::ops::Deref;
 #[cfg(feature = "secure")]
 use std::collections::HashMap;
 #[cfg(feature = "fast")]
 use foldhash::HashMap;
 
-use crate::types::*;
+use crate::{
+    error::{Limit, Malformed, Position, XmlError},
+    types::*,
+};
+
+mod cst;
+mod dtd;
+mod entity;
+mod reader;
+pub(crate) use cst::cst_document;
+pub use dtd::doc_type;
+use dtd::doc_type_in;
+pub use reader::{Reader, StreamReader};
+
+// NOTE:
+// What XML counts as whitespace, which unlike `char::is_whitespace` does not include
+// e.g. no-break spaces.
+pub(crate) const WHITESPACE: [char; 4] = [' ', '\t', '\r', '\n'];
+
+/// Limits on the work done while parsing, so that untrusted input cannot make the
+/// parser run out of memory or time. Going over one is a `XmlErrorKind::LimitExceeded`.
+///
+/// The defaults only bound what could blow up from a small input, i.e. entity
+/// expansion; the other limits should be set for untrusted input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// How deep references to entities may nest inside the replacement texts of other
+    /// entities.
+    pub max_entity_depth: usize,
+    /// How many bytes the replacement texts of entities may add up to, over the whole
+    /// document.
+    pub max_entity_expansion: usize,
+    /// How deep elements may nest, the root element being at depth 1. Also bounds how
+    /// deep groups may nest in the content models of the DOCTYPE.
+    pub max_depth: usize,
+    pub max_attributes_per_element: usize,
+    /// Longest element or attribute name, in bytes.
+    pub max_name_length: usize,
+    /// Longest text, CDATA section or attribute value, in bytes once references are
+    /// expanded.
+    pub max_text_length: usize,
+    /// How many elements, texts, CDATA sections, comments and processing instructions
+    /// the root element may contain, itself included.
+    pub max_total_nodes: usize,
+    /// Turns tabs and line ends in attribute values into spaces, and trims and collapses
+    /// the spaces of attributes that the DOCTYPE declares with a tokenized type (`ID`,
+    /// `NMTOKENS`, ...), as XML 1.0 §3.3.3 requires. Without it, values without references
+    /// are borrowed from the input as is.
+    pub normalize_attributes: bool,
+    /// How whitespace in text is handled, unless `xml:space="preserve"` asks to keep it.
+    pub whitespace: Whitespace,
+    pub duplicate_attributes: DuplicateAttributes,
+    /// Records where the tags, attributes and children of each element are in the input,
+    /// see `TagSpans`.
+    pub spans: bool,
+}
+
+/// What to do with an attribute that appears more than once in the same tag.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DuplicateAttributes {
+    /// Fail, as the spec requires.
+    #[default]
+    Error,
+    KeepFirst,
+    KeepLast,
+}
+
+/// Handling of the whitespace in the text content of elements. Whitespace outside of
+/// the root element is never kept.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Whitespace {
+    /// Text is kept as written, including text that is only whitespace.
+    Preserve,
+    /// Whitespace around text is removed, and text that is only whitespace dropped.
+    #[default]
+    Trim,
+    /// Like `Trim`, and runs of whitespace inside text become a single space, including
+    /// whitespace written as character references.
+    Collapse,
+}
 
+impl Default for ParseOptions {
+    fn default() -> Self {
+        ParseOptions {
+            max_entity_depth: 16,
+            max_entity_expansion: 1 << 20,
+            max_depth: usize::MAX,
+            max_attributes_per_element: usize::MAX,
+            max_name_length: usize::MAX,
+            max_text_length: usize::MAX,
+            max_total_nodes: usize::MAX,
+            normalize_attributes: true,
+            whitespace: Whitespace::default(),
+            duplicate_attributes: DuplicateAttributes::default(),
+            spans: false,
+        }
+    }
+}
+
+impl ParseOptions {
+    // NOTE:
+    // How whitespace is handled inside an element with the given `xml:space`, `outer`
+    // being how it is handled around the element.
+    fn whitespace_within(&self, outer: Whitespace, xml_space: Option<&str>) -> Whitespace {
+        match xml_space {
+            Some("preserve") => Whitespace::Preserve,
+            Some("default") => self.whitespace,
+            _ => outer,
+        }
+    }
+}
+
+// NOTE:
+// What the parsers need besides their input, i.e. the limits and the entities declared
+// by the DOCTYPE. See `entity.rs` for the expansion itself.
+pub(crate) struct State {
+    options: ParseOptions,
+    entities: HashMap<String, Option<String>>,
+    // The attributes declared for each element, and whether their type is tokenized.
+    attribute_types: HashMap<String, Vec<(String, bool)>>,
+    expanded: Cell<usize>,
+    nodes: Cell<usize>,
+}
+
+impl State {
+    pub(crate) fn new(options: ParseOptions) -> Self {
+        State {
+            options,
+            entities: HashMap::default(),
+            attribute_types: HashMap::default(),
+            expanded: Cell::new(0),
+            nodes: Cell::new(0),
+        }
+    }
+
+    // NOTE:
+    // Fails at `at` if `value` goes over `limit`.
+    fn check<'a, E>(&self, at: &'a str, value: usize, limit: Limit) -> Result<(), nom::Err<E>>
+    where
+        E: FromExternalError<&'a str, Malformed<'a>>,
+    {
+        if value <= limit.max() {
+            return Ok(());
+        }
+
+        Err(nom::Err::Failure(E::from_external_error(
+            at,
+            ErrorKind::TooLarge,
+            Malformed::LimitExceeded(limit),
+        )))
+    }
+
+    fn count_node<'a, E>(&self, at: &'a str) -> Result<(), nom::Err<E>>
+    where
+        E: FromExternalError<&'a str, Malformed<'a>>,
+    {
+        let nodes = self.nodes.get() + 1;
+        self.nodes.set(nodes);
+        self.check(at, nodes, Limit::TotalNodes(self.options.max_total_nodes))
+    }
+
+    // NOTE:
+    // What counts towards the limits so far, to be put back with `restore` when a token is
+    // thrown away to be parsed again, so that it does not count twice.
+    fn counters(&self) -> (usize, usize) {
+        (self.expanded.get(), self.nodes.get())
+    }
+
+    fn restore(&self, (expanded, nodes): (usize, usize)) {
+        self.expanded.set(expanded);
+        self.nodes.set(nodes);
+    }
+}
+
+// NOTE:
+// Turns slices of the input into `Span`s. Going forward from the last slice only goes
+// through the input in between, going back starts over from `origin`.
+struct Spanner<'a> {
+    origin: (Position, &'a str),
+    position: Position,
+    rest: &'a str,
+}
+
+impl<'a> Spanner<'a> {
+    fn new(position: Position, rest: &'a str) -> Self {
+        Spanner {
+            origin: (position, rest),
+            position,
+            rest,
+        }
+    }
+
+    fn position_of(&mut self, at: &'a str) -> Position {
+        if at.as_ptr() < self.rest.as_ptr() {
+            (self.position, self.rest) = self.origin;
+        }
+        let skipped = &self.rest[..self.rest.offset(at)];
+        self.position = self.position.advanced_by(skipped);
+        self.rest = &self.rest[skipped.len()..];
+        self.position
+    }
+
+    fn span_of(&mut self, part: &'a str) -> Span {
+        Span {
+            start: self.position_of(part),
+            end: self.position_of(&part[part.len()..]),
+        }
+    }
+}
+
+// `NameStartChar` production of the XML 1.0 spec, minus `:` which is reserved for
+// namespace prefixes.
+fn is_name_start_char(c: char) -> bool {
+    matches!(c,
+        'A'..='Z' | '_' | 'a'..='z'
+        | '\u{C0}'..='\u{D6}' | '\u{D8}'..='\u{F6}' | '\u{F8}'..='\u{2FF}'
+        | '\u{370}'..='\u{37D}' | '\u{37F}'..='\u{1FFF}' | '\u{200C}'..='\u{200D}'
+        | '\u{2070}'..='\u{218F}' | '\u{2C00}'..='\u{2FEF}' | '\u{3001}'..='\u{D7FF}'
+        | '\u{F900}'..='\u{FDCF}' | '\u{FDF0}'..='\u{FFFD}' | '\u{10000}'..='\u{EFFFF}'
+    )
+}
+
+// `NameChar` production of the XML 1.0 spec, minus `:`.
+fn is_name_char(c: char) -> bool {
+    is_name_start_char(c)
+        || matches!(c,
+            '-' | '.' | '0'..='9' | '\u{B7}' | '\u{300}'..='\u{36F}' | '\u{203F}'..='\u{2040}'
+        )
+}
+
+// NOTE:
+// An `NCName` from the namespaces spec, i.e. an XML `Name` without any `:`.
 fn xml_key<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, &'a str, E> {
-    take_while1(|c: char| c.is_alphanumeric() || "_-".contains(c))(i)
+    recognize(pair(satisfy(is_name_start_char), take_while(is_name_char)))(i)
+}
+
+// NOTE:
+// `prefix:local`, see `QName`.
+fn qualified_name<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, &'a str, E> {
+    context(
+        "name",
+        recognize(pair(xml_key, opt(pair(char(':'), cut(xml_key))))),
+    )(i)
 }
 
-fn xml_text<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, &'a str, E> {
-    map(take_till1(|c: char| "&<>".contains(c)), |r: &'a str| {
-        r.trim()
-    })(i)
+// NOTE:
+// For the serializer, which needs to know what the parser would accept.
+pub(crate) fn is_qualified_name(name: &str) -> bool {
+    all_consuming(qualified_name::<()>)(name).is_ok()
 }
 
-fn quote_delim<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, char, E> {
-    alt((char('\''), char('\"')))(i)
+pub(crate) fn is_pi_target(target: &str) -> bool {
+    all_consuming(xml_key::<()>)(target).is_ok() && !target.eq_ignore_ascii_case("xml")
+}
+
+// NOTE:
+// `name` is the text between `&` and `;`.
+fn decode_reference(name: &str) -> Option<char> {
+    match name {
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "amp" => Some('&'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        _ => {
+            let code = match name.strip_prefix("#x") {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => name.strip_prefix('#')?.parse().ok()?,
+            };
+            char::from_u32(code).filter(|&c| is_xml_char(c))
+        }
+    }
+}
+
+// `Char` production of the XML 1.0 spec.
+fn is_xml_char(c: char) -> bool {
+    matches!(c, '\t' | '\n' | '\r' | '\u{20}'..='\u{D7FF}' | '\u{E000}'..='\u{FFFD}' | '\u{10000}'..)
+}
+
+/// Replaces the predefined entities (`&lt;`, `&amp;`, ...) and character references
+/// (`&#60;`, `&#x3C;`) in `raw` by the characters they stand for.
+///
+/// Only allocates when `raw` actually contains a reference. Returns `None` if a
+/// reference is malformed or unknown.
+pub fn unescape(raw: &str) -> Option<Cow<'_, str>> {
+    if !raw.contains('&') {
+        return Some(Cow::Borrowed(raw));
+    }
+
+    let mut decoded = String::with_capacity(raw.len());
+    let mut rest = raw;
+
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        let end = rest[start..].find(';')? + start;
+        decoded.push(decode_reference(&rest[start + 1..end])?);
+        rest = &rest[end + 1..];
+    }
+    decoded.push_str(rest);
+
+    Some(Cow::Owned(decoded))
 }
 
-fn attribute_value<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+// NOTE:
+// Character references are checked here, references to entities only once expanded as
+// they depend on the DOCTYPE.
+fn reference<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
     i: &'a str,
 ) -> IResult<&'a str, &'a str, E> {
     context(
-        "attribute_value",
-        cut(delimited(
-            quote_delim,
-            // TODO:
-            // Ideally, this should support `"` or `'` depending on the delimiter used...
-            escaped(
-                take_till(|c: char| "\'\"".contains(c)),
-                '\\',
-                one_of(r#""n\"#),
-            ),
-            quote_delim,
+        "reference",
+        recognize(preceded(
+            char('&'),
+            cut(terminated(
+                verify(
+                    take_while1(|c: char| is_name_char(c) || c == '#' || c == ':'),
+                    |name: &str| match name.starts_with('#') {
+                        true => decode_reference(name).is_some(),
+                        false => name.starts_with(|c| c == ':' || is_name_start_char(c)),
+                    },
+                ),
+                char(';'),
+            )),
         )),
     )(i)
 }
 
-fn attribute_key_value<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+// NOTE:
+// `raw` is a slice of `i`, errors point into `i` so that they are suffixes of the input.
+fn expand_in<'a, E: FromExternalError<&'a str, Malformed<'a>>>(
+    state: &State,
     i: &'a str,
-) -> IResult<&'a str, (&'a str, &'a str), E> {
-    separated_pair(
-        preceded(multispace0, xml_key),
-        cut(preceded(multispace0, char('='))),
-        cut(preceded(multispace0, attribute_value)),
-    )
-    .parse(i)
+    raw: &'a str,
+    normalize: bool,
+) -> Result<Cow<'a, str>, nom::Err<E>> {
+    state.expand(raw, normalize).map_err(|(offset, m)| {
+        let at = &i[i.offset(raw) + offset..];
+        nom::Err::Failure(E::from_external_error(at, ErrorKind::MapRes, m))
+    })
 }
 
-fn xml_value<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+fn xml_text<'a, E>(
+    state: &State,
+    whitespace: Whitespace,
     i: &'a str,
-) -> IResult<&'a str, Xml, E> {
-    preceded(
-        multispace0,
-        alt((map(xml_text, |s| Xml::Text(s.into())), element)),
-    )
-    .parse(i)
+) -> IResult<&'a str, Cow<'a, str>, E>
+where
+    E: ParseError<&'a str> + ContextError<&'a str> + FromExternalError<&'a str, Malformed<'a>>,
+{
+    let (remaining, raw) = recognize(many1_count(alt((is_not("<&"), reference))))(i)?;
+    let text = match whitespace {
+        Whitespace::Preserve => expand_in(state, i, raw, false)?,
+        Whitespace::Trim => expand_in(state, i, raw.trim_matches(WHITESPACE), false)?,
+        Whitespace::Collapse => {
+            collapse_whitespace(expand_in(state, i, raw.trim_matches(WHITESPACE), false)?)
+        }
+    };
+    let max = state.options.max_text_length;
+    state.check(i, text.len(), Limit::TextLength(max))?;
+
+    Ok((remaining, text))
+}
+
+fn collapse_whitespace(text: Cow<'_, str>) -> Cow<'_, str> {
+    let is_space = |c| matches!(c, ' ' | '\t' | '\r' | '\n');
+    let mut previous = ' ';
+    let is_collapsed = text.chars().all(|c| {
+        let collapsed = !is_space(c) || (c == ' ' && !is_space(previous));
+        previous = c;
+        collapsed
+    });
+    if is_collapsed && !text.ends_with(is_space) {
+        return text;
+    }
+
+    let words: Vec<&str> = text.split(is_space).filter(|w| !w.is_empty()).collect();
+    Cow::Owned(words.join(" "))
 }
 
-fn attributes_hash<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+// NOTE:
+// The spec forbids `--` inside a comment, and the comment may not end with `-`
+// (i.e. `--->` is not a valid terminator).
+fn comment<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
     i: &'a str,
-) -> IResult<&'a str, HashMap<String, String>, E> {
+) -> IResult<&'a str, &'a str, E> {
     context(
-        "map",
-        map(
-            separated_list0(many1(one_of(" \t\r\n")), attribute_key_value),
-            |tuple_vec| {
-                tuple_vec
-                    .into_iter()
-                    .map(|(k, v)| (String::from(k), String::from(v)))
-                    .collect()
-            },
+        "comment",
+        preceded(
+            tag("<!--"),
+            cut(terminated(
+                verify(take_until("-->"), |c: &str| {
+                    !c.contains("--") && !c.ends_with('-')
+                }),
+                tag("-->"),
+            )),
         ),
     )(i)
 }
 
+fn cdata<'a, E>(state: &State, i: &'a str) -> IResult<&'a str, &'a str, E>
+where
+    E: ParseError<&'a str> + ContextError<&'a str> + FromExternalError<&'a str, Malformed<'a>>,
+{
+    let (remaining, cdata) = context(
+        "cdata",
+        preceded(
+            tag("<![CDATA["),
+            cut(terminated(take_until("]]>"), tag("]]>"))),
+        ),
+    )(i)?;
+    let max = state.options.max_text_length;
+    state.check(i, cdata.len(), Limit::TextLength(max))?;
+
+    Ok((remaining, cdata))
+}
+
 // NOTE:
-// This will return the tag.
-// We can use the Tag.value to determien the `closing_tag`
-fn opening_tag<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+// A literal between matching quotes, as used in the prolog. Nothing is unescaped.
+fn quoted<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, &'a str, E> {
+    alt((
+        delimited(char('"'), take_till(|c| c == '"'), char('"')),
+        delimited(char('\''), take_till(|c| c == '\''), char('\'')),
+    ))(i)
+}
+
+// NOTE:
+// Also returns the value as written, quotes included.
+fn attribute_value<'a, E>(state: &State, i: &'a str) -> IResult<&'a str, (&'a str, Cow<'a, str>), E>
+where
+    E: ParseError<&'a str> + ContextError<&'a str> + FromExternalError<&'a str, Malformed<'a>>,
+{
+    // NOTE:
+    // Backslashes are plain characters in XML, quotes can only be escaped by using the
+    // other kind of delimiter or a reference.
+    let text = |delimiter| recognize(many0_count(alt((is_not(delimiter), reference))));
+
+    let (remaining, raw) = context(
+        "attribute_value",
+        cut(alt((
+            delimited(char('\''), text("'&"), char('\'')),
+            delimited(char('"'), text("\"&"), char('"')),
+        ))),
+    )(i)?;
+    let value = expand_in(state, i, raw, state.options.normalize_attributes)?;
+    let max = state.options.max_text_length;
+    state.check(i, value.len(), Limit::TextLength(max))?;
+
+    Ok((remaining, (&i[..i.offset(remaining)], value)))
+}
+
+fn limited_name<'a, E>(state: &State, i: &'a str) -> IResult<&'a str, &'a str, E>
+where
+    E: ParseError<&'a str> + ContextError<&'a str> + FromExternalError<&'a str, Malformed<'a>>,
+{
+    let (remaining, name) = qualified_name(i)?;
+    let max = state.options.max_name_length;
+    state.check(i, name.len(), Limit::NameLength(max))?;
+
+    Ok((remaining, name))
+}
+
+// An attribute as written in a tag.
+struct Attribute<'a> {
+    name: &'a str,
+    // The value as written, quotes included.
+    quoted: &'a str,
+    value: Cow<'a, str>,
+}
+
+fn attribute_key_value<'a, E>(
+    state: &State,
+    element: &str,
+    i: &'a str,
+) -> IResult<&'a str, Attribute<'a>, E>
+where
+    E: ParseError<&'a str> + ContextError<&'a str> + FromExternalError<&'a str, Malformed<'a>>,
+{
+    let (remaining, (name, (quoted, value))) = separated_pair(
+        preceded(multispace0, |i| limited_name(state, i)),
+        cut(preceded(multispace0, char('='))),
+        cut(preceded(multispace0, |i| attribute_value(state, i))),
+    )
+    .parse(i)?;
+
+    let attribute = Attribute {
+        name,
+        quoted,
+        value: state.collapse_tokens(element, name, value),
+    };
+    Ok((remaining, attribute))
+}
+
+// NOTE:
+// Anything an element may contain besides other elements, which `element` parses itself.
+fn xml_value<'a, E>(state: &State, whitespace: Whitespace, i: &'a str) -> IResult<&'a str, Xml, E>
+where
+    E: ParseError<&'a str> + ContextError<&'a str> + FromExternalError<&'a str, Malformed<'a>>,
+{
+    alt((
+        map(comment, |c| Xml::Comment(c.into())),
+        map(processing_instruction, |(target, data)| {
+            Xml::ProcessingInstruction {
+                target: target.into(),
+                data: data.into(),
+            }
+        }),
+        map(|i| cdata(state, i), |c| Xml::CData(c.into())),
+        map(|i| xml_text(state, whitespace, i), |s| {
+            Xml::Text(s.into_owned())
+        }),
+    ))
+    .parse(i)
+}
+
+const MAX_SCANNED_ATTRIBUTES: usize = 8;
+
+fn attribute_list<'a, E>(
+    state: &State,
+    element: &str,
     i: &'a str,
-) -> IResult<&'a str, (Tag, bool), E> {
+) -> IResult<&'a str, Vec<Attribute<'a>>, E>
+where
+    E: ParseError<&'a str> + ContextError<&'a str> + FromExternalError<&'a str, Malformed<'a>>,
+{
+    let max = state.options.max_attributes_per_element;
+    let attribute = |i| context("attributes", |i| attribute_key_value(state, element, i))(i);
+    let (mut remaining, first) = opt(attribute)(i)?;
+    let mut attributes: Vec<_> = first.into_iter().collect();
+    // NOTE:
+    // Counted as they are parsed, so that the limit also bounds what is held in memory.
+    while !attributes.is_empty() {
+        state.check(i, attributes.len(), Limit::AttributesPerElement(max))?;
+        match preceded(many1(one_of(" \t\r\n")), attribute)(remaining) {
+            Ok((rest, a)) => {
+                remaining = rest;
+                attributes.push(a);
+            }
+            Err(nom::Err::Error(_)) => break,
+            Err(e) => return Err(e),
+        }
+    }
+
+    // NOTE:
+    // Tags mostly have a handful of attributes, which are quicker to scan than to hash,
+    // so where each one is in `unique` is only kept by name once there are more.
+    let mut seen: Option<HashMap<&str, usize>> = None;
+    let mut unique: Vec<Attribute> = Vec::with_capacity(attributes.len());
+    for attribute in attributes {
+        let name = attribute.name;
+        let found = match &seen {
+            Some(seen) => seen.get(name).copied(),
+            None => unique.iter().position(|a| a.name == name),
+        };
+        let Some(n) = found else {
+            unique.push(attribute);
+            if let Some(seen) = &mut seen {
+                seen.insert(name, unique.len() - 1);
+            } else if unique.len() > MAX_SCANNED_ATTRIBUTES {
+                seen = Some(unique.iter().enumerate().map(|(n, a)| (a.name, n)).collect());
+            }
+            continue;
+        };
+
+        match state.options.duplicate_attributes {
+            DuplicateAttributes::Error => {
+                return Err(nom::Err::Failure(E::from_external_error(
+                    &i[i.offset(name)..],
+                    ErrorKind::Verify,
+                    Malformed::DuplicateAttribute { name },
+                )))
+            }
+            DuplicateAttributes::KeepFirst => (),
+            DuplicateAttributes::KeepLast => unique[n] = attribute,
+        }
+    }
+
+    Ok((remaining, unique))
+}
+
+// NOTE:
+// The name and attributes of the tag at `i`, and whether it is self-closing.
+fn tag_parts<'a, E>(
+    state: &State,
+    i: &'a str,
+) -> IResult<&'a str, (&'a str, Vec<Attribute<'a>>, bool), E>
+where
+    E: ParseError<&'a str> + ContextError<&'a str> + FromExternalError<&'a str, Malformed<'a>>,
+{
+    // NOTE:
+    // Past `<` and what starts other markup, only a tag can follow, so an invalid name or
+    // rest of the tag is reported as such rather than as an unclosed parent element.
     map(
         tuple((
-            char('<'),
-            map(
-                separated_pair(xml_key, multispace0, attributes_hash),
-                |(value, attributes)| {
-                    let value: String = value.into();
-                    Tag { value, attributes }
+            terminated(char('<'), not(one_of("/!?"))),
+            flat_map(
+                cut(|i| limited_name(state, i)),
+                |name| {
+                    cut(map(
+                        preceded(multispace0, move |i| attribute_list(state, name, i)),
+                        move |attributes| (name, attributes),
+                    ))
                 },
             ),
-            preceded(
+            cut(preceded(
                 multispace0,
                 alt((
                     value(true, tag("/>")),  // Detect self-closing tags
                     value(false, char('>')), // Regular tags
                 )),
-            ),
+            )),
         )),
-        |(_, tag, is_self_closing)| (tag, is_self_closing),
+        |(_, (name, attributes), is_self_closing)| (name, attributes, is_self_closing),
     )(i)
 }
 
-// TODO:
-// Remove all the `clone`
-fn element<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+// NOTE:
+// The spans of the tag at `i`, which starts at `start` and is followed by `rest`.
+fn tag_spans(start: Position, i: &str, attributes: &[Attribute], rest: &str) -> Box<TagSpans> {
+    let mut spanner = Spanner::new(start, i);
+    let attributes = attributes
+        .iter()
+        .map(|a| (spanner.span_of(a.name), spanner.span_of(a.quoted)))
+        .collect();
+
+    Box::new(TagSpans {
+        opening: Span {
+            start,
+            end: spanner.position_of(rest),
+        },
+        closing: None,
+        attributes,
+        children: Vec::new(),
+    })
+}
+
+// NOTE:
+// This will return the tag.
+// We can use the Tag.value to determien the `closing_tag`
+// Spans are only recorded given the `start` of the tag.
+fn opening_tag<'a, E>(
+    state: &State,
+    start: Option<Position>,
     i: &'a str,
-) -> IResult<&'a str, Xml, E> {
-    let (remaining, (my_tag, is_self_closing)) = opening_tag(i)?;
-
-    if is_self_closing {
-        Ok((remaining, Xml::Element(my_tag, None)))
-    } else {
-        map(
-            terminated(
-                many0(xml_value),
-                preceded(
-                    multispace0,
-                    delimited(tag("</"), tag(my_tag.value.clone().deref()), char('>')),
-                ),
-            ),
-            move |vs| {
-                Xml::Element(
-                    Tag {
-                        value: my_tag.value.clone(),
-                        attributes: my_tag.attributes.clone(),
-                    },
-                    Some(vs),
-                )
-            },
-        )
-        .parse(remaining)
-    }
+) -> IResult<&'a str, (Tag, bool), E>
+where
+    E: ParseError<&'a str> + ContextError<&'a str> + FromExternalError<&'a str, Malformed<'a>>,
+{
+    let (remaining, (name, attributes, is_self_closing)) = tag_parts(state, i)?;
+    let tag = Tag {
+        value: name.into(),
+        spans: start.map(|start| tag_spans(start, i, &attributes, remaining)),
+        attributes: attributes
+            .into_iter()
+            .map(|a| (a.name.into(), a.value.into_owned()))
+            .collect(),
+    };
+
+    Ok((remaining, (tag, is_self_closing)))
 }
 
-fn xml_value_ref<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+fn closing_tag<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
     i: &'a str,
-) -> IResult<&'a str, XmlRef<'a>, E> {
+) -> IResult<&'a str, &'a str, E> {
     preceded(
         multispace0,
-        alt((map(xml_text, |s| XmlRef::Text(s)), element_ref)),
-    )
-    .parse(i)
+        delimited(tag("</"), qualified_name, preceded(multispace0, char('>'))),
+    )(i)
 }
 
-// TODO:
-// Return Iterator<(key, val)> ?
-fn attributes_hash_ref<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+// NOTE:
+// Once the opening tag at `opened` has been parsed, anything but its own closing tag
+// is a well-formedness error, so this never returns a recoverable `Err::Error`.
+fn end_of_element<'a, E>(
+    opened: &'a str,
+    is_own_name: impl Fn(&str) -> bool,
     i: &'a str,
-) -> IResult<&'a str, HashMap<&'a str, &'a str>, E> {
-    context(
-        "map",
-        map(
-            separated_list0(many1(one_of(" \t\r\n")), attribute_key_value),
-            |tuple_vec| {
-                tuple_vec
-                    .into_iter()
-                    .map(|(k, v)| (k, v))
-                    .collect()
-            },
-        ),
-    )(i)
+) -> IResult<&'a str, (), E>
+where
+    E: ParseError<&'a str> + ContextError<&'a str> + FromExternalError<&'a str, Malformed<'a>>,
+{
+    let at = i.trim_start_matches([' ', '\t', '\r', '\n']);
+    let malformed = match closing_tag::<E>(i) {
+        Ok((remaining, name)) if is_own_name(name) => return Ok((remaining, ())),
+        Ok(_) => Malformed::Mismatched {
+            opened,
+            closing: at,
+        },
+        Err(_) => Malformed::Unclosed { opened },
+    };
+
+    Err(nom::Err::Failure(E::from_external_error(
+        at,
+        ErrorKind::Tag,
+        malformed,
+    )))
 }
 
 // NOTE:
-// This will return the tag.
-// We can use the Tag.value to determien the `closing_tag`
-fn opening_tag_ref<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+// The elements that are still open are kept on a stack rather than parsed recursively,
+// so that only `max_depth` bounds how deep a document can be.
+fn element<'a, E>(state: &State, source: &'a str, i: &'a str) -> IResult<&'a str, Xml, E>
+where
+    E: ParseError<&'a str> + ContextError<&'a str> + FromExternalError<&'a str, Malformed<'a>>,
+{
+    // Where the opening tag of each open element starts, with the element's children so far
+    // and how whitespace is handled inside of it.
+    let mut open: Vec<(&'a str, Tag, Vec<Xml>, Whitespace)> = Vec::new();
+    let mut remaining = i;
+    let mut spanner = (state.options.spans).then(|| Spanner::new(Position::of(source, i), i));
+
+    loop {
+        let whitespace = open.last().map_or(state.options.whitespace, |(.., w)| *w);
+        let (at, _) = match (open.is_empty(), whitespace) {
+            (false, Whitespace::Preserve) => (remaining, ""),
+            _ => multispace0(remaining)?,
+        };
+
+        if let Some((_, my_tag, children, _)) = open.last_mut() {
+            match xml_value(state, whitespace, at) {
+                // NOTE:
+                // Text that is empty once expanded and trimmed is not a node.
+                Ok((rest, Xml::Text(ref text))) if text.is_empty() => {
+                    remaining = rest;
+                    continue;
+                }
+                Ok((rest, value)) => {
+                    state.count_node(at)?;
+                    if let (Some(spanner), Some(spans)) = (&mut spanner, &mut my_tag.spans) {
+                        // Text owns the whitespace trimmed from its start.
+                        let from = match value {
+                            Xml::Text(_) => remaining,
+                            _ => at,
+                        };
+                        spans.children.push(spanner.span_of(&from[..from.offset(rest)]));
+                    }
+                    children.push(value);
+                    remaining = rest;
+                    continue;
+                }
+                Err(nom::Err::Error(_)) => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        let start = spanner.as_mut().map(|spanner| spanner.position_of(at));
+        let (element, span) = match opening_tag(state, start, at) {
+            Ok((rest, (my_tag, is_self_closing))) => {
+                state.count_node(at)?;
+                state.check(at, open.len() + 1, Limit::Depth(state.options.max_depth))?;
+                remaining = rest;
+
+                if !is_self_closing {
+                    let xml_space = my_tag.attributes.get("xml:space").map(String::as_str);
+                    let inner = state.options.whitespace_within(whitespace, xml_space);
+                    open.push((at, my_tag, Vec::new(), inner));
+                    continue;
+                }
+                let span = my_tag.spans.as_ref().map(|spans| spans.element());
+                (Xml::Element(my_tag, None), span)
+            }
+            Err(nom::Err::Error(e)) => {
+                let Some((opened, mut my_tag, children, _)) = open.pop() else {
+                    return Err(nom::Err::Error(e));
+                };
+                let (rest, _) = end_of_element(opened, |name| my_tag.value == name, remaining)?;
+                if let (Some(spanner), Some(spans)) = (&mut spanner, &mut my_tag.spans) {
+                    let closing = remaining.trim_start_matches([' ', '\t', '\r', '\n']);
+                    spans.closing = Some(spanner.span_of(&closing[..closing.offset(rest)]));
+                }
+                remaining = rest;
+
+                let span = my_tag.spans.as_ref().map(|spans| spans.element());
+                (Xml::Element(my_tag, Some(children)), span)
+            }
+            Err(e) => return Err(e),
+        };
+
+        match open.last_mut() {
+            Some((_, parent, children, _)) => {
+                if let (Some(spans), Some(span)) = (&mut parent.spans, span) {
+                    spans.children.push(span);
+                }
+                children.push(element);
+            }
+            None => return Ok((remaining, element)),
+        }
+    }
+}
+
+fn xml_value_ref<'a, E>(
+    state: &State,
+    whitespace: Whitespace,
     i: &'a str,
-) -> IResult<&'a str, (TagRef<'a>, bool), E> {
-    map(
-        tuple((
-            char('<'),
+) -> IResult<&'a str, XmlRef<'a>, E>
+where
+    E: ParseError<&'a str> + ContextError<&'a str> + FromExternalError<&'a str, Malformed<'a>>,
+{
+    alt((
+        map(comment, XmlRef::Comment),
+        map(processing_instruction, |(target, data)| {
+            XmlRef::ProcessingInstruction { target, data }
+        }),
+        map(|i| cdata(state, i), XmlRef::CData),
+        map(|i| xml_text(state, whitespace, i), XmlRef::Text),
+    ))
+    .parse(i)
+}
+
+// NOTE:
+// See `opening_tag`.
+pub(crate) fn opening_tag_ref<'a, E>(
+    state: &State,
+    start: Option<Position>,
+    i: &'a str,
+) -> IResult<&'a str, (TagRef<'a>, bool), E>
+where
+    E: ParseError<&'a str> + ContextError<&'a str> + FromExternalError<&'a str, Malformed<'a>>,
+{
+    let (remaining, (name, attributes, is_self_closing)) = tag_parts(state, i)?;
+    let tag = TagRef {
+        value: name.into(),
+        spans: start.map(|start| tag_spans(start, i, &attributes, remaining)),
+        attributes: attributes.into_iter().map(|a| (a.name, a.value)).collect(),
+    };
+
+    Ok((remaining, (tag, is_self_closing)))
+}
+
+fn element_ref<'a, E>(state: &State, source: &'a str, i: &'a str) -> IResult<&'a str, XmlRef<'a>, E>
+where
+    E: ParseError<&'a str> + ContextError<&'a str> + FromExternalError<&'a str, Malformed<'a>>,
+{
+    // Where the opening tag of each open element starts, with the element's children so far
+    // and how whitespace is handled inside of it.
+    let mut open: Vec<(&'a str, TagRef<'a>, Vec<XmlRef<'a>>, Whitespace)> = Vec::new();
+    let mut remaining = i;
+    let mut spanner = (state.options.spans).then(|| Spanner::new(Position::of(source, i), i));
+
+    loop {
+        let whitespace = open.last().map_or(state.options.whitespace, |(.., w)| *w);
+        let (at, _) = match (open.is_empty(), whitespace) {
+            (false, Whitespace::Preserve) => (remaining, ""),
+            _ => multispace0(remaining)?,
+        };
+
+        if let Some((_, my_tag, children, _)) = open.last_mut() {
+            match xml_value_ref(state, whitespace, at) {
+                // NOTE:
+                // Text that is empty once expanded and trimmed is not a node.
+                Ok((rest, XmlRef::Text(ref text))) if text.is_empty() => {
+                    remaining = rest;
+                    continue;
+                }
+                Ok((rest, value)) => {
+                    state.count_node(at)?;
+                    if let (Some(spanner), Some(spans)) = (&mut spanner, &mut my_tag.spans) {
+                        // Text owns the whitespace trimmed from its start.
+                        let from = match value {
+                            XmlRef::Text(_) => remaining,
+                            _ => at,
+                        };
+                        spans.children.push(spanner.span_of(&from[..from.offset(rest)]));
+                    }
+                    children.push(value);
+                    remaining = rest;
+                    continue;
+                }
+                Err(nom::Err::Error(_)) => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        let start = spanner.as_mut().map(|spanner| spanner.position_of(at));
+        let (element, span) = match opening_tag_ref(state, start, at) {
+            Ok((rest, (my_tag, is_self_closing))) => {
+                state.count_node(at)?;
+                state.check(at, open.len() + 1, Limit::Depth(state.options.max_depth))?;
+                remaining = rest;
+
+                if !is_self_closing {
+                    let xml_space = my_tag.attributes.get("xml:space").map(|v| v.as_ref());
+                    let inner = state.options.whitespace_within(whitespace, xml_space);
+                    open.push((at, my_tag, Vec::new(), inner));
+                    continue;
+                }
+                let span = my_tag.spans.as_ref().map(|spans| spans.element());
+                (XmlRef::Element(my_tag, None), span)
+            }
+            Err(nom::Err::Error(e)) => {
+                let Some((opened, mut my_tag, children, _)) = open.pop() else {
+                    return Err(nom::Err::Error(e));
+                };
+                let (rest, _) = end_of_element(opened, |name| my_tag.value == name, remaining)?;
+                if let (Some(spanner), Some(spans)) = (&mut spanner, &mut my_tag.spans) {
+                    let closing = remaining.trim_start_matches([' ', '\t', '\r', '\n']);
+                    spans.closing = Some(spanner.span_of(&closing[..closing.offset(rest)]));
+                }
+                remaining = rest;
+
+                let span = my_tag.spans.as_ref().map(|spans| spans.element());
+                (XmlRef::Element(my_tag, Some(children)), span)
+            }
+            Err(e) => return Err(e),
+        };
+
+        match open.last_mut() {
+            Some((_, parent, children, _)) => {
+                if let (Some(spans), Some(span)) = (&mut parent.spans, span) {
+                    spans.children.push(span);
+                }
+                children.push(element);
+            }
+            None => return Ok((remaining, element)),
+        }
+    }
+}
+
+// NOTE:
+// Returns the target and the (possibly empty) data. The `xml` target is reserved for the
+// declaration, which `xml_declaration` parses where it is allowed.
+fn processing_instruction<'a, E>(i: &'a str) -> IResult<&'a str, (&'a str, &'a str), E>
+where
+    E: ParseError<&'a str> + ContextError<&'a str> + FromExternalError<&'a str, Malformed<'a>>,
+{
+    let (remaining, target) = preceded(tag("<?"), xml_key)(i)?;
+
+    if target.eq_ignore_ascii_case("xml") {
+        return Err(nom::Err::Failure(E::from_external_error(
+            i,
+            ErrorKind::Verify,
+            Malformed::MisplacedDeclaration,
+        )));
+    }
+
+    let (remaining, data) = context(
+        "processing_instruction",
+        cut(terminated(
             map(
-                separated_pair(xml_key, multispace0, attributes_hash_ref),
-                |(value, attributes)| {
-                    TagRef { value, attributes }
-                },
-            ),
-            preceded(
-                multispace0,
-                alt((
-                    value(true, tag("/>")),  // Detect self-closing tags
-                    value(false, char('>')), // Regular tags
-                )),
+                // NOTE:
+                // `cut` so that a missing `?>` is reported as such.
+                opt(preceded(multispace1, cut(take_until("?>")))),
+                Option::unwrap_or_default,
             ),
+            tag("?>"),
         )),
-        |(_, tag, is_self_closing)| (tag, is_self_closing),
-    )(i)
+    )(remaining)?;
+
+    Ok((remaining, (target, data)))
 }
 
-fn element_ref<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
-    i: &'a str,
-) -> IResult<&'a str, XmlRef<'a>, E> {
-    let (remaining, (my_tag, is_self_closing)) = opening_tag_ref(i)?;
-
-    if is_self_closing {
-        Ok((remaining, XmlRef::Element(my_tag, None)))
-    } else {
-        map(
-            terminated(
-                many0(xml_value_ref),
-                preceded(
-                    multispace0,
-                    delimited(tag("</"), tag(my_tag.value), char('>')),
-                ),
-            ),
-            // TODO:
-            // Avoid cloning `attributes`.
-            // Try :
-            //  - FnOnce
-            //  - A different `map` function that is `FnOnce`
-            move |vs| {
-                XmlRef::Element(
-                    TagRef {
-                        value: my_tag.value,
-                        attributes: my_tag.attributes.clone(),
-                    },
-                    Some(vs),
-                )
-            },
-        )
-        .parse(remaining)
-    }
+fn is_version_number(v: &str) -> bool {
+    v.strip_prefix("1.")
+        .is_some_and(|minor| !minor.is_empty() && minor.bytes().all(|b| b.is_ascii_digit()))
 }
 
-pub fn doc_type<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+fn is_encoding_name(v: &str) -> bool {
+    v.starts_with(|c: char| c.is_ascii_alphabetic())
+        && v.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "._-".contains(c))
+}
+
+// NOTE:
+// One of the `name="value"` pairs of the XML declaration, whose order is fixed.
+fn declaration_attribute<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    name: &'static str,
+    is_valid: fn(&str) -> bool,
+) -> impl FnMut(&'a str) -> IResult<&'a str, &'a str, E> {
+    preceded(
+        pair(multispace1, tag(name)),
+        cut(preceded(
+            tuple((multispace0, char('='), multispace0)),
+            context(name, verify(quoted, move |v: &str| is_valid(v))),
+        )),
+    )
+}
+
+pub fn xml_declaration<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
     i: &'a str,
-) -> IResult<&'a str, &'a str, E> {
-    delimited(preceded(multispace0, tag("<!DOCTYPE")), is_not("?>"), char('>'))(i)
+) -> IResult<&'a str, XmlDeclaration, E> {
+    preceded(
+        terminated(tag("<?xml"), peek(multispace1)),
+        cut(terminated(
+            map(
+                tuple((
+                    declaration_attribute("version", is_version_number),
+                    opt(declaration_attribute("encoding", is_encoding_name)),
+                    opt(declaration_attribute("standalone", |v| {
+                        v == "yes" || v == "no"
+                    })),
+                )),
+                |(version, encoding, standalone)| XmlDeclaration {
+                    version: version.into(),
+                    encoding: encoding.map(String::from),
+                    standalone: standalone.map(|s| s == "yes"),
+                },
+            ),
+            pair(multispace0, tag("?>")),
+        )),
+    )(i)
 }
 
 pub fn xml_meta<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
@@ -251,22 +1025,194 @@ pub fn xml_meta<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
     delimited(preceded(multispace0, tag("<?")), is_not("?>"), tag("?>"))(i)
 }
 
-// TODO:
-// Actually account for (use the) meta data
-pub fn root<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
-    i: &'a str,
-) -> IResult<&'a str, Xml, E> {
-    cut(preceded(
-        many0(alt((xml_meta, doc_type))),
-        delimited(opt(multispace0), element, opt(multispace0)),
-    ))(i)
+fn misc<'a, E>(i: &'a str) -> IResult<&'a str, Misc, E>
+where
+    E: ParseError<&'a str> + ContextError<&'a str> + FromExternalError<&'a str, Malformed<'a>>,
+{
+    preceded(
+        multispace0,
+        alt((
+            map(comment, |c| Misc::Comment(c.into())),
+            map(processing_instruction, |(target, data)| {
+                Misc::ProcessingInstruction {
+                    target: target.into(),
+                    data: data.into(),
+                }
+            }),
+        )),
+    )(i)
 }
 
-pub fn root_ref<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+// The parts of a `Document` before its root element, in order.
+type Prolog = (Option<XmlDeclaration>, Vec<Misc>, Option<Doctype>, Vec<Misc>);
+
+// NOTE:
+// The declaration has to be the very first thing in the document, which is also the only
+// place `Encoding::detect` looks for it.
+fn prolog<'a, E>(state: &State, i: &'a str) -> IResult<&'a str, Prolog, E>
+where
+    E: ParseError<&'a str> + ContextError<&'a str> + FromExternalError<&'a str, Malformed<'a>>,
+{
+    map(
+        tuple((
+            opt(xml_declaration),
+            many0(misc),
+            opt(preceded(multispace0, |i| doc_type_in(state, i))),
+            many0(misc),
+        )),
+        |(declaration, before_doctype, doctype, prolog)| match doctype {
+            Some(_) => (declaration, before_doctype, doctype, prolog),
+            None => (declaration, Vec::new(), doctype, before_doctype),
+        },
+    )(i)
+}
+
+// NOTE:
+// Also returns the `State` for the root element, which knows the entities declared by
+// the DOCTYPE.
+fn prolog_with_state<'a, E>(
+    options: ParseOptions,
     i: &'a str,
-) -> IResult<&'a str, XmlRef<'a>, E> {
-    cut(preceded(
-        many0(alt((xml_meta, doc_type))),
-        delimited(opt(multispace0), element_ref, opt(multispace0)),
-    ))(i)
+) -> IResult<&'a str, (Prolog, State), E>
+where
+    E: ParseError<&'a str> + ContextError<&'a str> + FromExternalError<&'a str, Malformed<'a>>,
+{
+    let mut state = State::new(options);
+    let (remaining, prolog) = prolog(&state, i)?;
+    if let Some(doctype) = &prolog.2 {
+        state.declare(doctype);
+    }
+
+    Ok((remaining, (prolog, state)))
+}
+
+pub fn document<'a, E>(i: &'a str) -> IResult<&'a str, Document, E>
+where
+    E: ParseError<&'a str> + ContextError<&'a str> + FromExternalError<&'a str, Malformed<'a>>,
+{
+    document_with(ParseOptions::default())(i)
+}
+
+pub fn document_with<'a, E>(
+    options: ParseOptions,
+) -> impl FnMut(&'a str) -> IResult<&'a str, Document, E>
+where
+    E: ParseError<&'a str> + ContextError<&'a str> + FromExternalError<&'a str, Malformed<'a>>,
+{
+    move |i| {
+        let (remaining, ((declaration, before_doctype, doctype, prolog), state)) =
+            cut(|i| prolog_with_state(options, i))(i)?;
+        let (remaining, (root, epilog)) = cut(pair(
+            preceded(opt(multispace0), |r| element(&state, i, r)),
+            terminated(many0(misc), pair(multispace0, context("end_of_input", eof))),
+        ))(remaining)?;
+
+        Ok((
+            remaining,
+            Document {
+                declaration,
+                before_doctype,
+                doctype,
+                prolog,
+                root,
+                epilog,
+            },
+        ))
+    }
+}
+
+pub fn root<'a, E>(i: &'a str) -> IResult<&'a str, Xml, E>
+where
+    E: ParseError<&'a str> + ContextError<&'a str> + FromExternalError<&'a str, Malformed<'a>>,
+{
+    root_with(ParseOptions::default())(i)
+}
+
+pub fn root_with<'a, E>(options: ParseOptions) -> impl FnMut(&'a str) -> IResult<&'a str, Xml, E>
+where
+    E: ParseError<&'a str> + ContextError<&'a str> + FromExternalError<&'a str, Malformed<'a>>,
+{
+    move |i| {
+        let (remaining, (_, state)) = cut(|i| prolog_with_state(options, i))(i)?;
+        let (remaining, root) = cut(delimited(
+            opt(multispace0),
+            |r| element(&state, i, r),
+            tuple((many0(misc), opt(multispace0))),
+        ))(remaining)?;
+
+        Ok((remaining, root))
+    }
+}
+
+pub fn root_ref<'a, E>(i: &'a str) -> IResult<&'a str, XmlRef<'a>, E>
+where
+    E: ParseError<&'a str> + ContextError<&'a str> + FromExternalError<&'a str, Malformed<'a>>,
+{
+    root_ref_with(ParseOptions::default())(i)
+}
+
+pub fn root_ref_with<'a, E>(
+    options: ParseOptions,
+) -> impl FnMut(&'a str) -> IResult<&'a str, XmlRef<'a>, E>
+where
+    E: ParseError<&'a str> + ContextError<&'a str> + FromExternalError<&'a str, Malformed<'a>>,
+{
+    move |i| {
+        let (remaining, (_, state)) = cut(|i| prolog_with_state(options, i))(i)?;
+        let (remaining, root) = cut(delimited(
+            opt(multispace0),
+            |r| element_ref(&state, i, r),
+            tuple((many0(misc), opt(multispace0))),
+        ))(remaining)?;
+
+        Ok((remaining, root))
+    }
+}
+
+/// Callbacks for `parse_with_handler`. Every method does nothing by default.
+pub trait Handler<'a> {
+    fn declaration(&mut self, _declaration: XmlDeclaration) {}
+
+    fn doc_type(&mut self, _doc_type: Doctype) {}
+
+    /// Self-closing elements get an `end_element` call right after this one.
+    fn start_element(&mut self, _tag: TagRef<'a>) {}
+
+    fn end_element(&mut self, _name: QNameRef<'a>) {}
+
+    fn text(&mut self, _text: Cow<'a, str>) {}
+
+    /// Forwards to `text` unless overridden.
+    fn cdata(&mut self, cdata: &'a str) {
+        self.text(Cow::Borrowed(cdata))
+    }
+
+    fn comment(&mut self, _comment: &'a str) {}
+
+    fn pi(&mut self, _target: &'a str, _data: &'a str) {}
+
+    /// Called with the error that is then returned by `parse_with_handler`.
+    fn error(&mut self, _error: &XmlError) {}
+}
+
+/// Walks `i` with a `Reader`, calling `handler` for each event without building a tree.
+pub fn parse_with_handler<'a>(i: &'a str, handler: &mut impl Handler<'a>) -> Result<(), XmlError> {
+    for event in Reader::new(i) {
+        match event {
+            Ok(EventRef::Declaration(d)) => handler.declaration(d),
+            Ok(EventRef::DocType(d)) => handler.doc_type(d),
+            Ok(EventRef::StartElement(t)) => handler.start_element(t),
+            Ok(EventRef::EndElement(n, _)) => handler.end_element(n),
+            Ok(EventRef::Text(t, _)) => handler.text(t),
+            Ok(EventRef::CData(c)) => handler.cdata(c),
+            Ok(EventRef::Comment(c)) => handler.comment(c),
+            Ok(EventRef::ProcessingInstruction { target, data }) => handler.pi(target, data),
+            Err(e) => {
+                handler.error(&e);
+                return Err(e);
+            }
+        }
+    }
+
+    Ok(())
 }
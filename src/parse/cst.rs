@@ -0,0 +1,170 @@
+use nom::{
+    branch::alt,
+    bytes::complete::{is_not, tag, take_till, take_until},
+    character::complete::{char, multispace0, multispace1, one_of},
+    combinator::{map, opt, recognize, value},
+    error::{ContextError, FromExternalError, ParseError},
+    multi::{many0, many0_count},
+    sequence::{pair, preceded, terminated, tuple},
+    IResult,
+};
+
+use super::{comment, doc_type, processing_instruction, qualified_name, xml_declaration};
+use crate::{error::Malformed, types::*};
+
+// NOTE:
+// The parsers below only keep track of where each piece of the input goes. They are
+// only run on input that already parsed as a `Document`, so they leave the
+// well-formedness checks out.
+
+fn attribute<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, CstAttribute, E> {
+    let (remaining, (leading_space, name, equals, quote)) = tuple((
+        multispace1,
+        qualified_name,
+        recognize(tuple((multispace0, char('='), multispace0))),
+        one_of("\"'"),
+    ))(i)?;
+    let (remaining, raw_value) = terminated(take_till(|c| c == quote), char(quote))(remaining)?;
+
+    Ok((
+        remaining,
+        CstAttribute {
+            leading_space: leading_space.into(),
+            name: name.into(),
+            equals: equals.into(),
+            quote,
+            raw_value: raw_value.into(),
+        },
+    ))
+}
+
+// NOTE:
+// Also returns whether the tag is an empty-element tag, i.e. `<a/>`.
+fn opening_tag<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, (CstElement, bool), E> {
+    let (remaining, (name, attributes, opening_space, is_empty)) = tuple((
+        preceded(char('<'), qualified_name),
+        many0(attribute),
+        multispace0,
+        alt((value(true, tag("/>")), value(false, char('>')))),
+    ))(i)?;
+
+    let element = CstElement {
+        name: name.into(),
+        attributes,
+        opening_space: opening_space.into(),
+        children: None,
+        closing_space: String::new(),
+    };
+    Ok((remaining, (element, is_empty)))
+}
+
+fn closing_tag<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, &'a str, E> {
+    map(
+        tuple((tag("</"), qualified_name, multispace0, char('>'))),
+        |(_, _, closing_space, _)| closing_space,
+    )(i)
+}
+
+fn content<'a, E>(i: &'a str) -> IResult<&'a str, CstNode, E>
+where
+    E: ParseError<&'a str> + ContextError<&'a str> + FromExternalError<&'a str, Malformed<'a>>,
+{
+    alt((
+        map(is_not("<"), |t: &str| CstNode::Text(t.into())),
+        map(
+            alt((
+                recognize(comment),
+                recognize(tuple((tag("<![CDATA["), take_until("]]>"), tag("]]>")))),
+                recognize(processing_instruction),
+            )),
+            |m: &str| CstNode::Markup(m.into()),
+        ),
+    ))(i)
+}
+
+// NOTE:
+// Iterative for the same reason as `element`.
+fn element<'a, E>(i: &'a str) -> IResult<&'a str, CstElement, E>
+where
+    E: ParseError<&'a str> + ContextError<&'a str> + FromExternalError<&'a str, Malformed<'a>>,
+{
+    // The elements that are still open, with their children so far.
+    let mut open: Vec<(CstElement, Vec<CstNode>)> = Vec::new();
+    let mut remaining = i;
+
+    loop {
+        if let Some((_, children)) = open.last_mut() {
+            match content::<E>(remaining) {
+                Ok((rest, node)) => {
+                    children.push(node);
+                    remaining = rest;
+                    continue;
+                }
+                Err(nom::Err::Error(_)) => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        let closed = match open.is_empty() {
+            true => None,
+            false => {
+                let (rest, closing_space) = opt(closing_tag)(remaining)?;
+                remaining = rest;
+                closing_space.and_then(|c| Some((c, open.pop()?)))
+            }
+        };
+
+        let element = match closed {
+            Some((closing_space, (mut element, children))) => {
+                element.children = Some(children);
+                element.closing_space = closing_space.into();
+                element
+            }
+            None => {
+                let (rest, (element, is_empty)) = opening_tag(remaining)?;
+                remaining = rest;
+                if !is_empty {
+                    open.push((element, Vec::new()));
+                    continue;
+                }
+                element
+            }
+        };
+
+        match open.last_mut() {
+            Some((_, children)) => children.push(CstNode::Element(element)),
+            None => return Ok((remaining, element)),
+        }
+    }
+}
+
+pub(crate) fn cst_document<'a, E>(i: &'a str) -> IResult<&'a str, CstDocument, E>
+where
+    E: ParseError<&'a str> + ContextError<&'a str> + FromExternalError<&'a str, Malformed<'a>>,
+{
+    let (remaining, prolog) = recognize(pair(
+        opt(xml_declaration),
+        many0_count(alt((
+            multispace1,
+            recognize(doc_type),
+            recognize(comment),
+            recognize(processing_instruction),
+        ))),
+    ))(i)?;
+    let (epilog, root) = element(remaining)?;
+
+    Ok((
+        "",
+        CstDocument {
+            prolog: prolog.into(),
+            root,
+            epilog: epilog.into(),
+        },
+    ))
+}
@@ -0,0 +1,463 @@
+use nom::{
+    branch::alt,
+    bytes::complete::{is_not, tag, take_while, take_while1},
+    character::complete::{char, multispace0, multispace1, one_of, satisfy},
+    combinator::{cut, map, opt, recognize, value, verify},
+    error::{context, ContextError, FromExternalError, ParseError},
+    multi::{many0, many0_count, many1, separated_list1},
+    sequence::{delimited, pair, preceded, separated_pair, terminated, tuple},
+    IResult,
+};
+
+use super::{
+    comment, is_name_char, is_name_start_char, processing_instruction, quoted, reference,
+    ParseOptions, State,
+};
+use crate::{
+    error::{Limit, Malformed},
+    types::*,
+};
+
+// NOTE:
+// Names in a DTD are plain XML `Name`s, which unlike element names in the document are not
+// split into prefix and local part.
+fn name<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, String, E> {
+    map(
+        recognize(pair(
+            satisfy(|c| c == ':' || is_name_start_char(c)),
+            take_while(|c| c == ':' || is_name_char(c)),
+        )),
+        String::from,
+    )(i)
+}
+
+fn nm_token<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, String, E> {
+    map(take_while1(|c| c == ':' || is_name_char(c)), String::from)(i)
+}
+
+// NOTE:
+// `keyword` followed by the whitespace that must separate it from what comes next.
+fn keyword<'a, E: ParseError<&'a str>>(
+    keyword: &'static str,
+) -> impl FnMut(&'a str) -> IResult<&'a str, &'a str, E> {
+    terminated(tag(keyword), multispace1)
+}
+
+fn literal<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, String, E> {
+    map(quoted, String::from)(i)
+}
+
+// NOTE:
+// Kept as written, but references are checked so that expanding the entity can rely on
+// them being well-formed.
+fn entity_value<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, String, E> {
+    let text = |delimiter| {
+        recognize(many0_count(alt((
+            is_not(delimiter),
+            reference,
+            recognize(tuple((char('%'), name, char(';')))),
+        ))))
+    };
+
+    context(
+        "entity_value",
+        map(
+            alt((
+                delimited(char('"'), text("\"%&"), char('"')),
+                delimited(char('\''), text("'%&"), char('\'')),
+            )),
+            String::from,
+        ),
+    )(i)
+}
+
+// NOTE:
+// The system literal is optional after a public id so that notations can share this,
+// `external_id_with_system` is for everywhere else.
+fn external_id<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, ExternalId, E> {
+    context(
+        "external_id",
+        alt((
+            map(
+                preceded(keyword("SYSTEM"), cut(literal)),
+                ExternalId::System,
+            ),
+            map(
+                preceded(
+                    keyword("PUBLIC"),
+                    cut(pair(literal, opt(preceded(multispace1, literal)))),
+                ),
+                |(public_id, system_id)| ExternalId::Public {
+                    public_id,
+                    system_id,
+                },
+            ),
+        )),
+    )(i)
+}
+
+fn external_id_with_system<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, ExternalId, E> {
+    verify(external_id, |id| {
+        !matches!(
+            id,
+            ExternalId::Public {
+                system_id: None,
+                ..
+            }
+        )
+    })(i)
+}
+
+fn repetition<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Repetition, E> {
+    map(opt(one_of("?*+")), |r| match r {
+        Some('?') => Repetition::Optional,
+        Some('*') => Repetition::ZeroOrMore,
+        Some('+') => Repetition::OneOrMore,
+        _ => Repetition::One,
+    })(i)
+}
+
+// NOTE:
+// A name, or a group like `(a | b)` or `(a, b)`; a single particle in parentheses is a
+// sequence of one. The groups that are still open are kept on a stack rather than parsed
+// recursively, like elements, and count towards `max_depth`, the outermost one being at
+// depth 1.
+fn content_particle<'a, E>(state: &State, i: &'a str) -> IResult<&'a str, ContentParticle, E>
+where
+    E: ParseError<&'a str> + ContextError<&'a str> + FromExternalError<&'a str, Malformed<'a>>,
+{
+    let max = state.options.max_depth;
+    // The particles of each open group so far, the separator of the group once known, and
+    // whether an enclosing group already has particles.
+    let mut open: Vec<(Vec<ContentParticle>, Option<char>, bool)> = Vec::new();
+    let mut remaining = i;
+
+    loop {
+        // Like `cut`, once any group has a particle.
+        let is_committed = open
+            .last()
+            .is_some_and(|(particles, _, outer)| *outer || !particles.is_empty());
+        let (rest, group) = opt(pair(char('('), multispace0))(remaining)?;
+        if group.is_some() {
+            state.check(remaining, open.len() + 1, Limit::Depth(max))?;
+            open.push((Vec::new(), None, is_committed));
+            remaining = rest;
+            continue;
+        }
+
+        let (rest, (name, times)) = match pair(name, repetition)(remaining) {
+            Err(nom::Err::Error(e)) if is_committed => return Err(nom::Err::Failure(e)),
+            parsed => parsed?,
+        };
+        remaining = rest;
+        let mut particle = ContentParticle {
+            particle: Particle::Name(name),
+            repetition: times,
+        };
+
+        if open.is_empty() {
+            return Ok((remaining, particle));
+        }
+
+        // Closes the groups that `particle` is the last one of.
+        while let Some((mut particles, separator, outer)) = open.pop() {
+            particles.push(particle);
+
+            let (rest, next) = preceded(multispace0, opt(one_of("|,")))(remaining)?;
+            match next {
+                Some(c) if separator.is_none() || separator == Some(c) => {
+                    open.push((particles, Some(c), outer));
+                    (remaining, _) = multispace0(rest)?;
+                    break;
+                }
+                _ => {
+                    let (rest, times) =
+                        cut(preceded(pair(multispace0, char(')')), repetition))(remaining)?;
+                    remaining = rest;
+                    particle = ContentParticle {
+                        particle: match separator {
+                            Some('|') => Particle::Choice(particles),
+                            _ => Particle::Sequence(particles),
+                        },
+                        repetition: times,
+                    };
+                    if open.is_empty() {
+                        return Ok((remaining, particle));
+                    }
+                }
+            }
+        }
+    }
+}
+
+// NOTE:
+// `(#PCDATA)` may omit the trailing `*`, but not once element names are listed.
+fn mixed<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, Vec<String>, E> {
+    preceded(
+        tuple((char('('), multispace0, tag("#PCDATA"))),
+        cut(alt((
+            terminated(
+                many1(preceded(tuple((multispace0, char('|'), multispace0)), name)),
+                tuple((multispace0, tag(")*"))),
+            ),
+            map(tuple((multispace0, char(')'), opt(char('*')))), |_| {
+                Vec::new()
+            }),
+        ))),
+    )(i)
+}
+
+fn content_spec<'a, E>(state: &State, i: &'a str) -> IResult<&'a str, ContentSpec, E>
+where
+    E: ParseError<&'a str> + ContextError<&'a str> + FromExternalError<&'a str, Malformed<'a>>,
+{
+    context(
+        "content_specification",
+        alt((
+            value(ContentSpec::Empty, tag("EMPTY")),
+            value(ContentSpec::Any, tag("ANY")),
+            map(mixed, ContentSpec::Mixed),
+            map(|i| content_particle(state, i), ContentSpec::Children),
+        )),
+    )(i)
+}
+
+fn element_decl<'a, E>(state: &State, i: &'a str) -> IResult<&'a str, MarkupDecl, E>
+where
+    E: ParseError<&'a str> + ContextError<&'a str> + FromExternalError<&'a str, Malformed<'a>>,
+{
+    preceded(
+        keyword("<!ELEMENT"),
+        cut(terminated(
+            map(
+                separated_pair(name, multispace1, |i| content_spec(state, i)),
+                |(name, content)| MarkupDecl::Element { name, content },
+            ),
+            pair(multispace0, char('>')),
+        )),
+    )(i)
+}
+
+fn names_between_bars<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    item: fn(&'a str) -> IResult<&'a str, String, E>,
+) -> impl FnMut(&'a str) -> IResult<&'a str, Vec<String>, E> {
+    delimited(
+        pair(char('('), multispace0),
+        separated_list1(tuple((multispace0, char('|'), multispace0)), item),
+        pair(multispace0, char(')')),
+    )
+}
+
+// NOTE:
+// Longer keywords come first, `ID` being a prefix of `IDREF`.
+fn attribute_type<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, AttributeType, E> {
+    context(
+        "attribute_type",
+        alt((
+            value(AttributeType::CData, tag("CDATA")),
+            value(AttributeType::IdRefs, tag("IDREFS")),
+            value(AttributeType::IdRef, tag("IDREF")),
+            value(AttributeType::Id, tag("ID")),
+            value(AttributeType::Entities, tag("ENTITIES")),
+            value(AttributeType::Entity, tag("ENTITY")),
+            value(AttributeType::NmTokens, tag("NMTOKENS")),
+            value(AttributeType::NmToken, tag("NMTOKEN")),
+            map(
+                preceded(keyword("NOTATION"), cut(names_between_bars(name))),
+                AttributeType::Notation,
+            ),
+            map(names_between_bars(nm_token), AttributeType::Enumeration),
+        )),
+    )(i)
+}
+
+fn default_decl<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, DefaultDecl, E> {
+    let attribute_value = || verify(literal, |v: &String| !v.contains('<'));
+
+    context(
+        "default_declaration",
+        alt((
+            value(DefaultDecl::Required, tag("#REQUIRED")),
+            value(DefaultDecl::Implied, tag("#IMPLIED")),
+            map(
+                preceded(keyword("#FIXED"), cut(attribute_value())),
+                DefaultDecl::Fixed,
+            ),
+            map(attribute_value(), DefaultDecl::Value),
+        )),
+    )(i)
+}
+
+fn attribute_def<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, AttributeDef, E> {
+    map(
+        tuple((
+            preceded(multispace1, name),
+            cut(preceded(multispace1, attribute_type)),
+            cut(preceded(multispace1, default_decl)),
+        )),
+        |(name, attribute_type, default)| AttributeDef {
+            name,
+            attribute_type,
+            default,
+        },
+    )(i)
+}
+
+fn attlist_decl<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, MarkupDecl, E> {
+    preceded(
+        keyword("<!ATTLIST"),
+        cut(terminated(
+            map(pair(name, many0(attribute_def)), |(element, attributes)| {
+                MarkupDecl::AttList {
+                    element,
+                    attributes,
+                }
+            }),
+            pair(multispace0, char('>')),
+        )),
+    )(i)
+}
+
+// NOTE:
+// Only general entities may be unparsed, i.e. carry an `NDATA` notation.
+fn entity_decl<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, MarkupDecl, E> {
+    let (remaining, parameter) =
+        preceded(keyword("<!ENTITY"), map(opt(keyword("%")), |p| p.is_some()))(i)?;
+
+    let notation = move |i| match parameter {
+        true => Ok((i, None)),
+        false => opt(preceded(tuple((multispace1, keyword("NDATA"))), cut(name)))(i),
+    };
+
+    cut(terminated(
+        map(
+            separated_pair(
+                name,
+                multispace1,
+                alt((
+                    map(entity_value, EntityValue::Internal),
+                    map(pair(external_id_with_system, notation), |(id, notation)| {
+                        EntityValue::External { id, notation }
+                    }),
+                )),
+            ),
+            move |(name, value)| MarkupDecl::Entity {
+                name,
+                parameter,
+                value,
+            },
+        ),
+        pair(multispace0, char('>')),
+    ))(remaining)
+}
+
+fn notation_decl<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, MarkupDecl, E> {
+    preceded(
+        keyword("<!NOTATION"),
+        cut(terminated(
+            map(
+                separated_pair(name, multispace1, external_id),
+                |(name, id)| MarkupDecl::Notation { name, id },
+            ),
+            pair(multispace0, char('>')),
+        )),
+    )(i)
+}
+
+fn markup_decl<'a, E>(state: &State, i: &'a str) -> IResult<&'a str, MarkupDecl, E>
+where
+    E: ParseError<&'a str> + ContextError<&'a str> + FromExternalError<&'a str, Malformed<'a>>,
+{
+    preceded(
+        multispace0,
+        alt((
+            context("element_declaration", |i| element_decl(state, i)),
+            context("attribute_list_declaration", attlist_decl),
+            context("entity_declaration", entity_decl),
+            context("notation_declaration", notation_decl),
+            map(delimited(char('%'), name, char(';')), |n| {
+                MarkupDecl::ParameterEntityReference(n)
+            }),
+            map(comment, |c| MarkupDecl::Comment(c.into())),
+            map(processing_instruction, |(target, data)| {
+                MarkupDecl::ProcessingInstruction {
+                    target: target.into(),
+                    data: data.into(),
+                }
+            }),
+        )),
+    )(i)
+}
+
+fn internal_subset<'a, E>(state: &State, i: &'a str) -> IResult<&'a str, Vec<MarkupDecl>, E>
+where
+    E: ParseError<&'a str> + ContextError<&'a str> + FromExternalError<&'a str, Malformed<'a>>,
+{
+    preceded(
+        char('['),
+        cut(terminated(
+            many0(|i| markup_decl(state, i)),
+            pair(multispace0, context("internal_subset_end", char(']'))),
+        )),
+    )(i)
+}
+
+/// `<!DOCTYPE name ExternalID? [internal subset]?>`.
+///
+/// Declarations from the external subset are not fetched, and parameter entity
+/// references are kept as such rather than expanded.
+pub fn doc_type<'a, E>(i: &'a str) -> IResult<&'a str, Doctype, E>
+where
+    E: ParseError<&'a str> + ContextError<&'a str> + FromExternalError<&'a str, Malformed<'a>>,
+{
+    doc_type_in(&State::new(ParseOptions::default()), i)
+}
+
+// NOTE:
+// Groups in content models nest no deeper than `max_depth`.
+pub(crate) fn doc_type_in<'a, E>(state: &State, i: &'a str) -> IResult<&'a str, Doctype, E>
+where
+    E: ParseError<&'a str> + ContextError<&'a str> + FromExternalError<&'a str, Malformed<'a>>,
+{
+    context(
+        "doctype",
+        preceded(
+            keyword("<!DOCTYPE"),
+            cut(terminated(
+                map(
+                    tuple((
+                        name,
+                        opt(preceded(multispace1, external_id_with_system)),
+                        opt(preceded(multispace0, |i| internal_subset(state, i))),
+                    )),
+                    |(name, external_id, internal_subset)| Doctype {
+                        name,
+                        external_id,
+                        internal_subset: internal_subset.unwrap_or_default(),
+                    },
+                ),
+                pair(multispace0, char('>')),
+            )),
+        ),
+    )(i)
+}
@@ -0,0 +1,221 @@
+use std::borrow::Cow;
+
+use super::{decode_reference, State};
+use crate::{
+    error::{EntityError, Limit, Malformed},
+    types::*,
+};
+
+impl State {
+    /// Makes the general entities of `doctype` available to `expand`, and the types of
+    /// its attributes to `collapse_tokens`. The first declaration of an entity or
+    /// attribute wins, as in the spec.
+    ///
+    /// Character references in replacement texts are decoded here, as the spec does when
+    /// declaring the entity, so that e.g. `&#9;` is normalized like a literal tab.
+    pub(super) fn declare(&mut self, doctype: &Doctype) {
+        for decl in &doctype.internal_subset {
+            match decl {
+                MarkupDecl::Entity {
+                    name,
+                    parameter: false,
+                    value,
+                } => {
+                    let replacement = match value {
+                        EntityValue::Internal(text) => Some(decode_char_references(text)),
+                        EntityValue::External { .. } => None,
+                    };
+                    self.entities.entry(name.clone()).or_insert(replacement);
+                }
+                MarkupDecl::AttList {
+                    element,
+                    attributes,
+                } => {
+                    let declared = self.attribute_types.entry(element.clone()).or_default();
+                    for a in attributes {
+                        if declared.iter().all(|(name, _)| *name != a.name) {
+                            let tokenized = a.attribute_type != AttributeType::CData;
+                            declared.push((a.name.clone(), tokenized));
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Replaces the references in `raw` by what they stand for. On error, also returns
+    /// the offset in `raw` of the reference that could not be expanded.
+    ///
+    /// With `normalize`, whitespace other than from character references becomes a
+    /// space, as in attribute values.
+    ///
+    /// The references in `raw` must be well-formed, as checked by `reference`.
+    pub(super) fn expand<'a>(
+        &self,
+        raw: &'a str,
+        normalize: bool,
+    ) -> Result<Cow<'a, str>, (usize, Malformed<'a>)> {
+        if !(raw.contains('&') || normalize && raw.contains(['\t', '\n', '\r'])) {
+            return Ok(Cow::Borrowed(raw));
+        }
+
+        let mut expanded = String::with_capacity(raw.len());
+        let mut rest = raw;
+
+        while let Some(start) = rest.find('&') {
+            push_text(&mut expanded, &rest[..start], normalize);
+            let reference = &rest[start..];
+            let end = reference.find(';').unwrap_or(reference.len());
+            self.expand_reference(
+                &reference[1..end],
+                &mut expanded,
+                normalize,
+                &mut Vec::new(),
+            )
+            .map_err(|m| (raw.len() - reference.len(), m))?;
+            rest = reference.get(end + 1..).unwrap_or_default();
+        }
+        push_text(&mut expanded, rest, normalize);
+
+        Ok(Cow::Owned(expanded))
+    }
+
+    /// Trims `value` and collapses its runs of spaces if the DOCTYPE declares `attribute`
+    /// of `element` with a tokenized type. Only done with `normalize_attributes`.
+    pub(super) fn collapse_tokens<'a>(
+        &self,
+        element: &str,
+        attribute: &str,
+        value: Cow<'a, str>,
+    ) -> Cow<'a, str> {
+        let tokenized = self
+            .attribute_types
+            .get(element)
+            .and_then(|declared| declared.iter().find(|(name, _)| name == attribute))
+            .is_some_and(|&(_, tokenized)| tokenized);
+        let is_collapsed =
+            !value.starts_with(' ') && !value.ends_with(' ') && !value.contains("  ");
+        if !self.options.normalize_attributes || !tokenized || is_collapsed {
+            return value;
+        }
+
+        let tokens: Vec<&str> = value.split(' ').filter(|t| !t.is_empty()).collect();
+        Cow::Owned(tokens.join(" "))
+    }
+
+    // NOTE:
+    // The replacement text may only contain text and references, markup is rejected
+    // rather than parsed.
+    fn expand_reference<'s>(
+        &'s self,
+        name: &'s str,
+        expanded: &mut String,
+        normalize: bool,
+        open: &mut Vec<&'s str>,
+    ) -> Result<(), Malformed<'static>> {
+        if let Some(c) = decode_reference(name) {
+            expanded.push(c);
+            return Ok(());
+        }
+
+        let entity_error = |error| Malformed::Entity {
+            name: name.into(),
+            error,
+        };
+        let replacement = match self.entities.get(name) {
+            Some(Some(replacement)) => replacement,
+            Some(None) => return Err(entity_error(EntityError::External)),
+            None => return Err(entity_error(EntityError::Undefined)),
+        };
+        if open.contains(&name) {
+            return Err(entity_error(EntityError::Recursive));
+        }
+        if open.len() >= self.options.max_entity_depth {
+            return Err(Malformed::LimitExceeded(Limit::EntityDepth(
+                self.options.max_entity_depth,
+            )));
+        }
+
+        open.push(name);
+        let mut rest = replacement.as_str();
+        while let Some(start) = rest.find(['&', '<']) {
+            self.grow(expanded, &rest[..start], normalize)?;
+            let reference = &rest[start..];
+            if reference.starts_with('<') {
+                return Err(entity_error(EntityError::Markup));
+            }
+            let end = reference.find(';').unwrap_or(reference.len());
+            self.expand_reference(&reference[1..end], expanded, normalize, open)?;
+            rest = reference.get(end + 1..).unwrap_or_default();
+        }
+        self.grow(expanded, rest, normalize)?;
+        open.pop();
+
+        Ok(())
+    }
+
+    // NOTE:
+    // Only replacement texts count towards `max_entity_expansion`, over the whole
+    // document rather than per reference.
+    fn grow(
+        &self,
+        expanded: &mut String,
+        text: &str,
+        normalize: bool,
+    ) -> Result<(), Malformed<'static>> {
+        let total = self.expanded.get() + text.len();
+        if total > self.options.max_entity_expansion {
+            return Err(Malformed::LimitExceeded(Limit::EntityExpansion(
+                self.options.max_entity_expansion,
+            )));
+        }
+
+        self.expanded.set(total);
+        push_text(expanded, text, normalize);
+        Ok(())
+    }
+}
+
+// NOTE:
+// References that are not valid character references are left to `expand` to report.
+fn decode_char_references(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find("&#") {
+        decoded.push_str(&rest[..start]);
+        let reference = &rest[start..];
+        let len = reference.find(';').map_or(reference.len(), |end| end + 1);
+        match decode_reference(reference[1..len].trim_end_matches(';')) {
+            Some(c) => decoded.push(c),
+            None => decoded.push_str(&reference[..len]),
+        }
+        rest = &reference[len..];
+    }
+    decoded.push_str(rest);
+
+    decoded
+}
+
+// NOTE:
+// `\r\n` counts as a single space, since line ends would have been normalized to `\n`
+// beforehand.
+fn push_text(expanded: &mut String, text: &str, normalize: bool) {
+    if !normalize {
+        expanded.push_str(text);
+        return;
+    }
+
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\r' => {
+                chars.next_if_eq(&'\n');
+                expanded.push(' ');
+            }
+            '\t' | '\n' => expanded.push(' '),
+            c => expanded.push(c),
+        }
+    }
+}
@@ -0,0 +1,516 @@
+use nom::{
+    branch::alt,
+    combinator::map,
+    error::{ContextError, FromExternalError, ParseError},
+    IResult,
+};
+use std::{io::BufRead, str};
+
+use super::{
+    cdata, closing_tag, comment, doc_type_in, opening_tag_ref, processing_instruction,
+    xml_declaration, xml_text, ParseOptions, State, Whitespace, WHITESPACE,
+};
+use crate::{
+    error::{ErrorTrace, Limit, Malformed, Position, XmlError, XmlErrorKind},
+    types::*,
+};
+
+// NOTE:
+// A single piece of markup or run of text, before `Nesting` has checked that it is
+// allowed where it appears.
+enum Token<'a> {
+    Start(TagRef<'a>, bool),
+    End(&'a str),
+    Event(EventRef<'a>),
+}
+
+impl Token<'_> {
+    // NOTE:
+    // Text that is empty once expanded and trimmed is checked like other text, but is
+    // neither counted nor yielded, like in the tree parsers.
+    fn is_empty_text(&self) -> bool {
+        matches!(self, Token::Event(EventRef::Text(t, _)) if t.is_empty())
+    }
+}
+
+// NOTE:
+// `start` is where `i` is, to record the spans of start tags with.
+fn token<'a, E>(
+    state: &State,
+    whitespace: Whitespace,
+    start: Position,
+    i: &'a str,
+) -> IResult<&'a str, Token<'a>, E>
+where
+    E: ParseError<&'a str> + ContextError<&'a str> + FromExternalError<&'a str, Malformed<'a>>,
+{
+    // NOTE:
+    // Text goes first since some of the markup parsers skip the whitespace before them,
+    // which may be preserved text.
+    alt((
+        map(
+            |i| xml_text(state, whitespace, i),
+            |t| Token::Event(EventRef::Text(t, None)),
+        ),
+        map(comment, |c| Token::Event(EventRef::Comment(c))),
+        map(|i| cdata(state, i), |c| Token::Event(EventRef::CData(c))),
+        map(xml_declaration, |d| Token::Event(EventRef::Declaration(d))),
+        map(processing_instruction, |(target, data)| {
+            Token::Event(EventRef::ProcessingInstruction { target, data })
+        }),
+        map(
+            |i| doc_type_in(state, i),
+            |d| Token::Event(EventRef::DocType(d)),
+        ),
+        map(closing_tag, Token::End),
+        map(
+            |i| opening_tag_ref(state, state.options.spans.then_some(start), i),
+            |(t, is_self_closing)| Token::Start(t, is_self_closing),
+        ),
+    ))(i)
+}
+
+// NOTE:
+// The well-formedness checks that need to remember earlier tokens, shared by
+// `Reader` and `StreamReader`.
+#[derive(Default)]
+struct Nesting {
+    open: Vec<(String, Position, Whitespace)>,
+    root_closed: bool,
+    started: bool,
+    has_doctype: bool,
+    nodes: usize,
+}
+
+impl Nesting {
+    fn whitespace(&self, options: &ParseOptions) -> Whitespace {
+        self.open.last().map_or(options.whitespace, |(.., w)| *w)
+    }
+
+    // NOTE:
+    // Whitespace before the next token is part of it when preserved, i.e. only in
+    // elements.
+    fn skip_whitespace<'a>(&self, options: &ParseOptions, input: &'a str) -> &'a str {
+        match (self.open.is_empty(), self.whitespace(options)) {
+            (false, Whitespace::Preserve) => input,
+            _ => input.trim_start_matches(WHITESPACE),
+        }
+    }
+
+    fn expecting(&self) -> &'static str {
+        if self.root_closed {
+            "end of input after the root element"
+        } else {
+            "root element"
+        }
+    }
+
+    // NOTE:
+    // The limits that depend on the tokens before `token`, which the parsers of `State`
+    // cannot check on their own. Only the root element and its content count as nodes,
+    // like for the tree parsers.
+    fn check_limits(&mut self, token: &Token, options: &ParseOptions) -> Result<(), XmlErrorKind> {
+        let is_start = matches!(token, Token::Start(..));
+        if is_start && self.open.len() >= options.max_depth {
+            return Err(XmlErrorKind::LimitExceeded(Limit::Depth(options.max_depth)));
+        }
+
+        let is_event = matches!(token, Token::Event(_)) && !token.is_empty_text();
+        if is_start || (!self.open.is_empty() && is_event) {
+            self.nodes += 1;
+            if self.nodes > options.max_total_nodes {
+                return Err(XmlErrorKind::LimitExceeded(Limit::TotalNodes(
+                    options.max_total_nodes,
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check(
+        &mut self,
+        token: &Token,
+        at: Position,
+        options: &ParseOptions,
+    ) -> Result<(), XmlErrorKind> {
+        let outside_root = self.open.is_empty();
+        let started = std::mem::replace(&mut self.started, true);
+
+        match token {
+            // The declaration has to be the very first thing, see `parse::prolog`.
+            Token::Event(EventRef::Declaration(_)) if started || at != Position::START => {
+                Err(XmlErrorKind::MisplacedDeclaration)
+            }
+            Token::Start(..) if self.root_closed => {
+                Err(XmlErrorKind::Expected(self.expecting().into()))
+            }
+            Token::Start(_, true) => {
+                self.root_closed = outside_root;
+                Ok(())
+            }
+            Token::Start(tag, false) => {
+                let xml_space = tag.attributes.get("xml:space").map(|v| v.as_ref());
+                let whitespace = options.whitespace_within(self.whitespace(options), xml_space);
+                self.open.push((tag.value.to_string(), at, whitespace));
+                Ok(())
+            }
+            Token::End(name) => match self.open.pop() {
+                Some((expected, ..)) if expected == *name => {
+                    self.root_closed = self.open.is_empty();
+                    Ok(())
+                }
+                Some((expected, opened_at, _)) => Err(XmlErrorKind::MismatchedTag {
+                    expected,
+                    found: name.to_string(),
+                    opened_at,
+                }),
+                None => Err(XmlErrorKind::Expected(format!(
+                    "{}, found `</{name}>`",
+                    self.expecting()
+                ))),
+            },
+            Token::Event(EventRef::Text(..) | EventRef::CData(_)) if outside_root => {
+                Err(XmlErrorKind::Expected(self.expecting().into()))
+            }
+            // NOTE:
+            // At most one DOCTYPE, and only before the root element.
+            Token::Event(EventRef::DocType(_)) => match self.open.last() {
+                Some((name, opened_at, _)) => Err(XmlErrorKind::UnclosedElement {
+                    name: name.clone(),
+                    opened_at: *opened_at,
+                }),
+                None if self.root_closed || self.has_doctype => {
+                    Err(XmlErrorKind::Expected(self.expecting().into()))
+                }
+                None => {
+                    self.has_doctype = true;
+                    Ok(())
+                }
+            },
+            Token::Event(_) => Ok(()),
+        }
+    }
+
+    fn finish(&self) -> Result<(), XmlErrorKind> {
+        match self.open.last() {
+            Some((name, opened_at, _)) => Err(XmlErrorKind::UnclosedElement {
+                name: name.clone(),
+                opened_at: *opened_at,
+            }),
+            None if !self.root_closed => Err(XmlErrorKind::Expected(self.expecting().into())),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Pull parser over a whole document held in memory, yielding `EventRef`s that borrow
+/// from it.
+///
+/// Stops after the first error.
+pub struct Reader<'a> {
+    source: &'a str,
+    rest: &'a str,
+    position: Position,
+    state: State,
+    nesting: Nesting,
+    pending_end: Option<QNameRef<'a>>,
+    done: bool,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(source: &'a str) -> Self {
+        Reader::with_options(source, ParseOptions::default())
+    }
+
+    pub fn with_options(source: &'a str, options: ParseOptions) -> Self {
+        Reader {
+            source,
+            rest: source,
+            position: Position::START,
+            state: State::new(options),
+            nesting: Nesting::default(),
+            pending_end: None,
+            done: false,
+        }
+    }
+
+    fn fail(&mut self, e: XmlError) -> Option<Result<EventRef<'a>, XmlError>> {
+        self.done = true;
+        Some(Err(e))
+    }
+}
+
+impl<'a> Iterator for Reader<'a> {
+    type Item = Result<EventRef<'a>, XmlError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        if let Some(name) = self.pending_end.take() {
+            return Some(Ok(EventRef::EndElement(name, None)));
+        }
+
+        let options = &self.state.options;
+        let at = self.nesting.skip_whitespace(options, self.rest);
+        let position = self
+            .position
+            .advanced_by(&self.rest[..self.rest.len() - at.len()]);
+
+        if at.is_empty() {
+            self.done = true;
+            return self
+                .nesting
+                .finish()
+                .err()
+                .map(|kind| Err(XmlError::new(self.source, position, kind)));
+        }
+
+        let whitespace = self.nesting.whitespace(options);
+        let (rest, token) = match token::<ErrorTrace>(&self.state, whitespace, position, at) {
+            Ok(parsed) => parsed,
+            Err(e) => return self.fail(XmlError::from_nom(self.source, e)),
+        };
+        let checked = self
+            .nesting
+            .check_limits(&token, &self.state.options)
+            .and_then(|_| self.nesting.check(&token, position, &self.state.options));
+        if let Err(kind) = checked {
+            return self.fail(XmlError::new(self.source, position, kind));
+        }
+        if let Token::Event(EventRef::DocType(doctype)) = &token {
+            self.state.declare(doctype);
+        }
+
+        let before = std::mem::replace(
+            &mut self.position,
+            position.advanced_by(&at[..at.len() - rest.len()]),
+        );
+        self.rest = rest;
+        if token.is_empty_text() {
+            return self.next();
+        }
+
+        let span = |start| {
+            self.state.options.spans.then_some(Span {
+                start,
+                end: self.position,
+            })
+        };
+        Some(Ok(match token {
+            Token::Start(tag, is_self_closing) => {
+                if is_self_closing {
+                    self.pending_end = Some(tag.value.clone());
+                }
+                EventRef::StartElement(tag)
+            }
+            Token::End(name) => EventRef::EndElement(name.into(), span(position)),
+            Token::Event(EventRef::Text(t, _)) => EventRef::Text(t, span(before)),
+            Token::Event(e) => e,
+        }))
+    }
+}
+
+/// Like `Reader`, but pulls the document from a `BufRead` as it goes instead of holding
+/// all of it, so the events are owned.
+///
+/// A token that straddles the end of what has been read so far is parsed again once more
+/// input is available. Other malformed tokens are reported right away.
+pub struct StreamReader<R> {
+    reader: R,
+    buffer: String,
+    // Bytes at the start of `buffer` that have already been turned into events.
+    consumed: usize,
+    // Position of `buffer[consumed..]` in the whole input.
+    position: Position,
+    // Trailing bytes of an UTF-8 sequence cut in half by the end of a read.
+    undecoded: Vec<u8>,
+    eof: bool,
+    state: State,
+    nesting: Nesting,
+    pending_end: Option<QName>,
+    done: bool,
+}
+
+impl<R: BufRead> StreamReader<R> {
+    pub fn new(reader: R) -> Self {
+        StreamReader::with_options(reader, ParseOptions::default())
+    }
+
+    pub fn with_options(reader: R, options: ParseOptions) -> Self {
+        StreamReader {
+            reader,
+            buffer: String::new(),
+            consumed: 0,
+            position: Position::START,
+            undecoded: Vec::new(),
+            eof: false,
+            state: State::new(options),
+            nesting: Nesting::default(),
+            pending_end: None,
+            done: false,
+        }
+    }
+
+    // Drops the consumed part of `buffer` and reads until what is left has at least doubled.
+    //
+    // NOTE:
+    // A token that straddles the end of `buffer` is parsed again from its start once more
+    // input is there, so growing the buffer by a fixed amount each time would make long
+    // tokens take quadratic time.
+    fn fill(&mut self) -> Result<(), XmlErrorKind> {
+        self.buffer.drain(..self.consumed);
+        self.consumed = 0;
+
+        let wanted = 2 * self.buffer.len();
+        while !self.eof && self.buffer.len() <= wanted {
+            self.read_chunk()?;
+        }
+
+        Ok(())
+    }
+
+    // Appends the next chunk of input to `buffer`, keeping back an UTF-8 sequence that the
+    // chunk cuts in half.
+    fn read_chunk(&mut self) -> Result<(), XmlErrorKind> {
+        let chunk = match self.reader.fill_buf() {
+            Ok(chunk) => chunk,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => return Ok(()),
+            Err(e) => return Err(XmlErrorKind::Io(e.to_string())),
+        };
+        if chunk.is_empty() {
+            self.eof = true;
+            return match self.undecoded.is_empty() {
+                true => Ok(()),
+                false => Err(XmlErrorKind::Io(
+                    "stream did not contain valid UTF-8".into(),
+                )),
+            };
+        }
+
+        let read = chunk.len();
+        self.undecoded.extend_from_slice(chunk);
+        self.reader.consume(read);
+
+        let valid = match str::from_utf8(&self.undecoded) {
+            Ok(s) => s.len(),
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(_) => {
+                return Err(XmlErrorKind::Io(
+                    "stream did not contain valid UTF-8".into(),
+                ))
+            }
+        };
+        self.buffer
+            .push_str(str::from_utf8(&self.undecoded[..valid]).unwrap_or_default());
+        self.undecoded.drain(..valid);
+
+        Ok(())
+    }
+
+    fn fail(&mut self, e: XmlError) -> Option<Result<Event, XmlError>> {
+        self.done = true;
+        Some(Err(e))
+    }
+}
+
+// NOTE:
+// Whether reading more input could make the error go away.
+fn is_truncation(e: &nom::Err<ErrorTrace>) -> bool {
+    match e {
+        nom::Err::Error(e) | nom::Err::Failure(e) => e.is_truncation(),
+        nom::Err::Incomplete(_) => true,
+    }
+}
+
+impl<R: BufRead> Iterator for StreamReader<R> {
+    type Item = Result<Event, XmlError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        if let Some(name) = self.pending_end.take() {
+            return Some(Ok(Event::EndElement(name, None)));
+        }
+
+        loop {
+            let input = &self.buffer[self.consumed..];
+            let options = &self.state.options;
+            let at = self.nesting.skip_whitespace(options, input);
+
+            if at.is_empty() && self.eof {
+                self.done = true;
+                return self
+                    .nesting
+                    .finish()
+                    .err()
+                    .map(|kind| Err(XmlError::within(input, at, self.position, kind)));
+            }
+
+            if !at.is_empty() {
+                let whitespace = self.nesting.whitespace(options);
+                let position = self.position.advanced_by(&input[..input.len() - at.len()]);
+                let counters = self.state.counters();
+                match token::<ErrorTrace>(&self.state, whitespace, position, at) {
+                    // Only trust a token that ends before the end of the buffer, text
+                    // may otherwise continue in the next chunk.
+                    Ok((rest, token)) if !rest.is_empty() || self.eof => {
+                        let checked = self
+                            .nesting
+                            .check_limits(&token, &self.state.options)
+                            .and_then(|_| {
+                                self.nesting.check(&token, position, &self.state.options)
+                            });
+                        if let Err(kind) = checked {
+                            let e = XmlError::within(input, at, self.position, kind);
+                            return self.fail(e);
+                        }
+                        if let Token::Event(EventRef::DocType(doctype)) = &token {
+                            self.state.declare(doctype);
+                        }
+
+                        let end = position.advanced_by(&at[..at.len() - rest.len()]);
+                        if token.is_empty_text() {
+                            self.position = end;
+                            self.consumed = self.buffer.len() - rest.len();
+                            continue;
+                        }
+                        let span = |start| self.state.options.spans.then_some(Span { start, end });
+                        let event = match token {
+                            Token::Start(tag, is_self_closing) => {
+                                if is_self_closing {
+                                    self.pending_end = Some(tag.value.clone().into());
+                                }
+                                Event::StartElement(tag.into())
+                            }
+                            Token::End(name) => {
+                                Event::EndElement(QNameRef::from(name).into(), span(position))
+                            }
+                            Token::Event(EventRef::Text(t, _)) => {
+                                Event::Text(t.into_owned(), span(self.position))
+                            }
+                            Token::Event(e) => e.into(),
+                        };
+
+                        self.position = end;
+                        self.consumed = self.buffer.len() - rest.len();
+                        return Some(Ok(event));
+                    }
+                    Err(e) if self.eof || !is_truncation(&e) => {
+                        let e = XmlError::from_nom_within(input, self.position, e);
+                        return self.fail(e);
+                    }
+                    _ => self.state.restore(counters),
+                }
+            }
+
+            if let Err(kind) = self.fill() {
+                let input = &self.buffer[self.consumed..];
+                let e = XmlError::within(input, "", self.position, kind);
+                return self.fail(e);
+            }
+        }
+    }
+}
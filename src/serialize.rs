@@ -1,37 +1,450 @@
-use crate::types::*;
+use std::{borrow::Cow, error::Error, fmt, slice};
+
+use crate::{
+    parse::{is_pi_target, is_qualified_name, WHITESPACE},
+    types::*,
+};
 
 const INDENTATION: usize = 4;
+// NOTE:
+// Deeper elements are indented as much as at this depth, otherwise the output of deep
+// documents would grow with the square of their depth.
+const MAX_INDENTED_DEPTH: usize = 32;
+
+/// A part of a tree that has no XML syntax that would parse back to it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SerializeError {
+    /// An element or attribute name that is not a `prefix:local` or `local` XML name.
+    Name(String),
+    /// A comment that contains `--` or ends with `-`.
+    Comment(String),
+    /// A processing instruction whose target is not a name or is `xml`, or whose data
+    /// contains `?>` or starts with whitespace.
+    ProcessingInstruction { target: String, data: String },
+}
+
+impl fmt::Display for SerializeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SerializeError::Name(name) => write!(f, "`{name}` is not a valid name"),
+            SerializeError::Comment(c) => {
+                write!(f, "comment `{c}` contains `--` or ends with `-`")
+            }
+            SerializeError::ProcessingInstruction { target, data } => {
+                write!(
+                    f,
+                    "processing instruction `<?{target} {data}?>` cannot be written"
+                )
+            }
+        }
+    }
+}
+
+impl Error for SerializeError {}
 
+/// Writes `x` as indented XML that `Xml::from_input_str` parses back to `x`, but for
+/// empty and adjacent texts.
 // TODO: Pretty options
-pub fn to_string(x: Xml) -> String {
+pub fn to_string(x: Xml) -> Result<String, SerializeError> {
+    let mut lines: Vec<String> = Vec::new();
+    // The elements whose children are being written, with the children left to write.
+    let mut open: Vec<(&Tag, slice::Iter<Xml>)> = Vec::new();
+    let mut next = Some(&x);
+
+    loop {
+        if let Some(x) = next.take() {
+            let indentation = indentation(open.len());
+            match x {
+                Xml::Element(t, Some(children)) => {
+                    lines.push(indentation + &tag_to_string(t, false)?);
+                    open.push((t, children.iter()));
+                }
+                // NOTE:
+                // Only the first line is indented, the others belong to the content.
+                x => lines.push(indentation + &leaf_to_string(x)?),
+            }
+        }
+
+        let Some((t, children)) = open.last_mut() else {
+            break;
+        };
+        match children.next() {
+            Some(child) => next = Some(child),
+            None => {
+                let closing = format!("</{}>", t.value);
+                open.pop();
+                lines.push(indentation(open.len()) + &closing);
+            }
+        }
+    }
+
+    Ok(lines.join("\n"))
+}
+
+fn indentation(depth: usize) -> String {
+    " ".repeat(INDENTATION * depth.min(MAX_INDENTED_DEPTH))
+}
+
+// NOTE:
+// Everything but elements with children, which `to_string` takes care of.
+fn leaf_to_string(x: &Xml) -> Result<String, SerializeError> {
     match x {
-        Xml::Element(ref t @ Tag { ref value, .. }, Some(children)) => format!(
-            "{open_tag}\n{serialized_children}\n</{value}>",
-            open_tag = tag_to_string(t.clone(), false),
-            serialized_children = children
-                .into_iter()
-                .map(|x| to_string(x)
-                    .lines()
-                    .map(|l| " ".repeat(INDENTATION) + l)
-                    .collect::<Vec<_>>()
-                    .join("\n"))
-                .collect::<Vec<_>>()
-                .join("\n")
-        ),
-        Xml::Element(t, None) => tag_to_string(t, true),
-        Xml::Text(s) => s,
-    }
-}
-
-fn tag_to_string(Tag { value, attributes }: Tag, is_self_closed: bool) -> String {
-    let attributes_str = attributes
+        Xml::Element(t, _) => tag_to_string(t, true),
+        Xml::Text(s) => Ok(escape_text(s).into_owned()),
+        // NOTE:
+        // `]]>` cannot appear inside a CDATA section, so it is split across two sections.
+        Xml::CData(s) => Ok(format!(
+            "<![CDATA[{}]]>",
+            s.replace("]]>", "]]]]><![CDATA[>")
+        )),
+        Xml::Comment(c) => comment_to_string(c),
+        Xml::ProcessingInstruction { target, data } => pi_to_string(target, data),
+    }
+}
+
+fn comment_to_string(c: &str) -> Result<String, SerializeError> {
+    match c.contains("--") || c.ends_with('-') {
+        true => Err(SerializeError::Comment(c.into())),
+        false => Ok(format!("<!--{c}-->")),
+    }
+}
+
+// NOTE:
+// Whitespace between the target and the data is not part of the data.
+fn pi_to_string(target: &str, data: &str) -> Result<String, SerializeError> {
+    let is_malformed = data.contains("?>") || data.starts_with([' ', '\t', '\r', '\n']);
+    if !is_pi_target(target) || is_malformed {
+        return Err(SerializeError::ProcessingInstruction {
+            target: target.into(),
+            data: data.into(),
+        });
+    }
+
+    match data.is_empty() {
+        true => Ok(format!("<?{target}?>")),
+        false => Ok(format!("<?{target} {data}?>")),
+    }
+}
+
+pub fn document_to_string(d: Document) -> Result<String, SerializeError> {
+    let mut lines = Vec::new();
+
+    lines.extend(d.declaration.as_ref().map(declaration_to_string));
+    for m in &d.before_doctype {
+        lines.push(misc_to_string(m)?);
+    }
+    if let Some(doctype) = &d.doctype {
+        lines.push(doctype_to_string(doctype)?);
+    }
+    for m in &d.prolog {
+        lines.push(misc_to_string(m)?);
+    }
+    lines.push(to_string(d.root)?);
+    for m in &d.epilog {
+        lines.push(misc_to_string(m)?);
+    }
+
+    Ok(lines.join("\n"))
+}
+
+/// Writes `d` back exactly as it was parsed, but for the changes made to it since.
+pub fn cst_to_string(d: &CstDocument) -> String {
+    let mut s = d.prolog.clone();
+    // The elements whose children are being written, with the children left to write.
+    let mut open: Vec<(&CstElement, std::slice::Iter<CstNode>)> = Vec::new();
+    let mut next = Some(&d.root);
+
+    loop {
+        if let Some(e) = next.take() {
+            s += &format!("<{}", e.name);
+            for a in &e.attributes {
+                let q = a.quote;
+                s += &format!(
+                    "{}{}{}{q}{}{q}",
+                    a.leading_space, a.name, a.equals, a.raw_value
+                );
+            }
+            s += &e.opening_space;
+            match &e.children {
+                Some(children) => {
+                    s.push('>');
+                    open.push((e, children.iter()));
+                }
+                None => s += "/>",
+            }
+        }
+
+        let Some((e, children)) = open.last_mut() else {
+            break;
+        };
+        match children.next() {
+            Some(CstNode::Element(child)) => next = Some(child),
+            Some(CstNode::Text(t) | CstNode::Markup(t)) => s += t,
+            None => {
+                s += &format!("</{}{}>", e.name, e.closing_space);
+                open.pop();
+            }
+        }
+    }
+
+    s + &d.epilog
+}
+
+fn misc_to_string(m: &Misc) -> Result<String, SerializeError> {
+    match m {
+        Misc::Comment(c) => comment_to_string(c),
+        Misc::ProcessingInstruction { target, data } => pi_to_string(target, data),
+    }
+}
+
+fn declaration_to_string(d: &XmlDeclaration) -> String {
+    let mut s = format!("<?xml version=\"{}\"", d.version);
+    if let Some(encoding) = &d.encoding {
+        s += &format!(" encoding=\"{encoding}\"");
+    }
+    if let Some(standalone) = d.standalone {
+        s += if standalone {
+            " standalone=\"yes\""
+        } else {
+            " standalone=\"no\""
+        };
+    }
+    s + "?>"
+}
+
+pub fn doctype_to_string(d: &Doctype) -> Result<String, SerializeError> {
+    let mut s = format!("<!DOCTYPE {}", d.name);
+    if let Some(id) = &d.external_id {
+        s += &format!(" {}", external_id_to_string(id));
+    }
+    if !d.internal_subset.is_empty() {
+        s += " [\n";
+        for decl in &d.internal_subset {
+            s += &format!(
+                "{}{}\n",
+                " ".repeat(INDENTATION),
+                markup_decl_to_string(decl)?
+            );
+        }
+        s += "]";
+    }
+    Ok(s + ">")
+}
+
+// NOTE:
+// DTD literals cannot be escaped, so the delimiter is picked to not clash with the content.
+fn literal_to_string(l: &str) -> String {
+    match l.contains('"') {
+        true => format!("'{l}'"),
+        false => format!("\"{l}\""),
+    }
+}
+
+fn external_id_to_string(id: &ExternalId) -> String {
+    match id {
+        ExternalId::System(system_id) => format!("SYSTEM {}", literal_to_string(system_id)),
+        ExternalId::Public {
+            public_id,
+            system_id,
+        } => {
+            let mut s = format!("PUBLIC {}", literal_to_string(public_id));
+            if let Some(system_id) = system_id {
+                s += &format!(" {}", literal_to_string(system_id));
+            }
+            s
+        }
+    }
+}
+
+// NOTE:
+// Iterative, see `ContentParticle`.
+fn content_particle_to_string(cp: &ContentParticle) -> String {
+    let repetition = |cp: &ContentParticle| match cp.repetition {
+        Repetition::One => "",
+        Repetition::Optional => "?",
+        Repetition::ZeroOrMore => "*",
+        Repetition::OneOrMore => "+",
+    };
+    let mut s = String::new();
+    // The groups being written, with their separator and the particles left to write.
+    let mut open: Vec<(&ContentParticle, &str, slice::Iter<ContentParticle>)> = Vec::new();
+    let mut next = Some(cp);
+
+    loop {
+        if let Some(cp) = next.take() {
+            match &cp.particle {
+                Particle::Name(n) => s += &format!("{n}{}", repetition(cp)),
+                Particle::Choice(cps) => open.push((cp, " | ", cps.iter())),
+                Particle::Sequence(cps) => open.push((cp, ", ", cps.iter())),
+            }
+        }
+
+        let Some((group, separator, particles)) = open.last_mut() else {
+            return s;
+        };
+        let is_first = particles.len() == group.particle.particles().len();
+        match particles.next() {
+            Some(cp) => {
+                s += if is_first { "(" } else { separator };
+                next = Some(cp);
+            }
+            None => {
+                // An empty group is never parsed, but may be built by hand.
+                if is_first {
+                    s.push('(');
+                }
+                s += &format!("){}", repetition(group));
+                open.pop();
+            }
+        }
+    }
+}
+
+fn attribute_def_to_string(a: &AttributeDef) -> String {
+    let attribute_type = match &a.attribute_type {
+        AttributeType::CData => "CDATA".into(),
+        AttributeType::Id => "ID".into(),
+        AttributeType::IdRef => "IDREF".into(),
+        AttributeType::IdRefs => "IDREFS".into(),
+        AttributeType::Entity => "ENTITY".into(),
+        AttributeType::Entities => "ENTITIES".into(),
+        AttributeType::NmToken => "NMTOKEN".into(),
+        AttributeType::NmTokens => "NMTOKENS".into(),
+        AttributeType::Notation(names) => format!("NOTATION ({})", names.join(" | ")),
+        AttributeType::Enumeration(tokens) => format!("({})", tokens.join(" | ")),
+    };
+    let default = match &a.default {
+        DefaultDecl::Required => "#REQUIRED".into(),
+        DefaultDecl::Implied => "#IMPLIED".into(),
+        DefaultDecl::Fixed(v) => format!("#FIXED {}", literal_to_string(v)),
+        DefaultDecl::Value(v) => literal_to_string(v),
+    };
+    format!("{} {attribute_type} {default}", a.name)
+}
+
+fn markup_decl_to_string(decl: &MarkupDecl) -> Result<String, SerializeError> {
+    let s = match decl {
+        MarkupDecl::Element { name, content } => {
+            let content = match content {
+                ContentSpec::Empty => "EMPTY".into(),
+                ContentSpec::Any => "ANY".into(),
+                ContentSpec::Mixed(names) if names.is_empty() => "(#PCDATA)".into(),
+                ContentSpec::Mixed(names) => format!("(#PCDATA | {})*", names.join(" | ")),
+                ContentSpec::Children(cp) => content_particle_to_string(cp),
+            };
+            format!("<!ELEMENT {name} {content}>")
+        }
+        MarkupDecl::AttList {
+            element,
+            attributes,
+        } => {
+            let mut s = format!("<!ATTLIST {element}");
+            for a in attributes {
+                s += &format!(" {}", attribute_def_to_string(a));
+            }
+            s + ">"
+        }
+        MarkupDecl::Entity {
+            name,
+            parameter,
+            value,
+        } => {
+            let value = match value {
+                EntityValue::Internal(v) => literal_to_string(v),
+                EntityValue::External { id, notation: None } => external_id_to_string(id),
+                EntityValue::External {
+                    id,
+                    notation: Some(n),
+                } => format!("{} NDATA {n}", external_id_to_string(id)),
+            };
+            let percent = if *parameter { "% " } else { "" };
+            format!("<!ENTITY {percent}{name} {value}>")
+        }
+        MarkupDecl::Notation { name, id } => {
+            format!("<!NOTATION {name} {}>", external_id_to_string(id))
+        }
+        MarkupDecl::ParameterEntityReference(name) => format!("%{name};"),
+        MarkupDecl::Comment(c) => return comment_to_string(c),
+        MarkupDecl::ProcessingInstruction { target, data } => return pi_to_string(target, data),
+    };
+    Ok(s)
+}
+
+fn tag_to_string(t: &Tag, is_self_closed: bool) -> Result<String, SerializeError> {
+    let name = t.value.to_string();
+    if let Some(invalid) = std::iter::once(name.as_str())
+        .chain(t.attributes.keys().map(String::as_str))
+        .find(|name| !is_qualified_name(name))
+    {
+        return Err(SerializeError::Name(invalid.into()));
+    }
+
+    let attributes_str = t
+        .attributes
         .iter()
-        .map(|(k, v)| format!("{}=\"{}\"", k, v))
+        .map(|(k, v)| format!("{k}=\"{}\"", escape_attribute(v)))
         .collect::<Vec<_>>()
         .join(" ");
-    format!(
+    Ok(format!(
         "<{}{closing_delim}",
-        format!("{value} {attributes_str}").trim(),
+        format!("{name} {attributes_str}").trim(),
         closing_delim = if is_self_closed { "/>" } else { ">" }
-    )
+    ))
+}
+
+fn escape_with<'a>(s: &'a str, escape: impl Fn(char) -> Option<&'static str>) -> Cow<'a, str> {
+    if !s.chars().any(|c| escape(c).is_some()) {
+        return Cow::Borrowed(s);
+    }
+
+    let mut escaped = String::with_capacity(s.len() + 8);
+    for c in s.chars() {
+        match escape(c) {
+            Some(e) => escaped.push_str(e),
+            None => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+// NOTE:
+// The parser trims whitespace off text before expanding references, so whitespace at
+// either end is written as character references to keep it. So is `\r`, which would
+// otherwise be read as a line end.
+fn escape_text(s: &str) -> Cow<'_, str> {
+    let escape = |s| {
+        escape_with(s, |c| match c {
+            '&' => Some("&amp;"),
+            '<' => Some("&lt;"),
+            '>' => Some("&gt;"),
+            '\r' => Some("&#13;"),
+            _ => None,
+        })
+    };
+    let trimmed = s.trim_matches(WHITESPACE);
+    if trimmed.len() == s.len() {
+        return escape(s);
+    }
+
+    let start = s.len() - s.trim_start_matches(WHITESPACE).len();
+    let end = start + trimmed.len();
+    let references =
+        |w: &str| -> String { w.chars().map(|c| format!("&#{};", u32::from(c))).collect() };
+    Cow::Owned(references(&s[..start]) + &escape(trimmed) + &references(&s[end..]))
+}
+
+// NOTE:
+// Whitespace other than ` ` is written as a character reference, otherwise
+// attribute-value normalization would turn it into a space on the way back in.
+pub(crate) fn escape_attribute(s: &str) -> Cow<'_, str> {
+    escape_with(s, |c| match c {
+        '&' => Some("&amp;"),
+        '<' => Some("&lt;"),
+        '"' => Some("&quot;"),
+        '\'' => Some("&apos;"),
+        '\t' => Some("&#9;"),
+        '\n' => Some("&#10;"),
+        '\r' => Some("&#13;"),
+        _ => None,
+    })
 }
@@ -1,14 +1,10 @@
-#[cfg(feature = "secure")]
-use std::collections::HashMap;
-#[cfg(feature = "fast")]
-use foldhash::{HashMap, HashMapExt};
+use std::borrow::Cow;
 
 use crate::{serialize::*, types::*};
 
 #[test]
 fn parses_xml() {
-    let data = "
-            <?xml version=\"1.0\"?>
+    let data = "<?xml version=\"1.0\"?>
             <?xml-stylesheet href=\"catalog.xsl\" type=\"text/xsl\"?>
             <catalog>
                 <product description=\"Cardigan Sweater\" product_image=\"cardigan.jpg\">
@@ -26,7 +22,8 @@ fn parses_xml() {
         Xml::Element(
             Tag {
                 value: "catalog".into(),
-                attributes: HashMap::new(),
+                attributes: AttributeMap::default(),
+                spans: None,
             },
             Some(vec![Xml::Element(
                 Tag {
@@ -38,6 +35,7 @@ fn parses_xml() {
                         ),
                         (String::from("product_image"), String::from("cardigan.jpg"))
                     ].into_iter().collect(),
+                    spans: None,
                 },
                 Some(vec![Xml::Element(
                     Tag {
@@ -45,19 +43,22 @@ fn parses_xml() {
                         attributes: 
                             [(String::from("gender"), String::from("Mens")),
                         ].into_iter().collect(),
+                        spans: None,
                     },
                     Some(vec![
                         Xml::Element(
                             Tag {
                                 value: "item_number".into(),
-                                attributes: HashMap::new(),
+                                attributes: AttributeMap::default(),
+                                spans: None,
                             },
                             Some(vec![Xml::Text("QWZ5671".into(),),],),
                         ),
                         Xml::Element(
                             Tag {
                                 value: "price".into(),
-                                attributes: HashMap::new(),
+                                attributes: AttributeMap::default(),
+                                spans: None,
                             },
                             Some(vec![Xml::Text("39.95".into(),),],),
                         ),
@@ -71,8 +72,7 @@ fn parses_xml() {
 
 #[test]
 fn duplicate_tags_ok() {
-    let data = "
-            <?xml version=\"1.0\"?>
+    let data = "<?xml version=\"1.0\"?>
             <?xml-stylesheet href=\"prices.xsl\" type=\"text/xsl\"?>
             <prices>
                 <price
@@ -90,7 +90,8 @@ fn duplicate_tags_ok() {
         Xml::Element(
             Tag {
                 value: "prices".into(),
-                attributes: HashMap::new(),
+                attributes: AttributeMap::default(),
+                spans: None,
             },
             Some(vec![
                 Xml::Element(
@@ -100,6 +101,7 @@ fn duplicate_tags_ok() {
                             ("val".into(), "19.95".into()),
                             ("val1".into(), "9.95".into())
                         ].into_iter().collect(),
+                        spans: None,
                     },
                     None
                 ),
@@ -107,6 +109,7 @@ fn duplicate_tags_ok() {
                     Tag {
                         value: "price".into(),
                         attributes: [("val".into(), "29.95".into())].into_iter().collect(),
+                        spans: None,
                     },
                     None
                 ),
@@ -114,6 +117,7 @@ fn duplicate_tags_ok() {
                     Tag {
                         value: "price".into(),
                         attributes: [("val".into(), "39.95".into())].into_iter().collect(),
+                        spans: None,
                     },
                     None
                 ),
@@ -121,6 +125,7 @@ fn duplicate_tags_ok() {
                     Tag {
                         value: "price".into(),
                         attributes: [("val".into(), "49.95".into())].into_iter().collect(),
+                        spans: None,
                     },
                     None
                 ),
@@ -131,8 +136,7 @@ fn duplicate_tags_ok() {
 
 #[test]
 fn multi_line_attributes() {
-    let data = "
-            <?xml version=\"1.0\"?>
+    let data = "<?xml version=\"1.0\"?>
             <?xml-stylesheetew href=\"prices.xsl\" type=\"text/xsl\"?>
             <prices>
                 <price
@@ -148,7 +152,8 @@ fn multi_line_attributes() {
         Xml::Element(
             Tag {
                 value: "prices".into(),
-                attributes: HashMap::new(),
+                attributes: AttributeMap::default(),
+                spans: None,
             },
             Some(vec![
                 Xml::Element(
@@ -158,6 +163,7 @@ fn multi_line_attributes() {
                             ("val".into(), "9.95".into()),
                             ("val1".into(), "19.95".into())
                         ].into_iter().collect(),
+                        spans: None,
                     },
                     None
                 ),
@@ -165,6 +171,7 @@ fn multi_line_attributes() {
                     Tag {
                         value: "price".into(),
                         attributes: [("val".into(), "29.95".into())].into_iter().collect(),
+                        spans: None,
                     },
                     None
                 ),
@@ -173,9 +180,6 @@ fn multi_line_attributes() {
     );
 }
 
-// FIXME:
-// This test only passes half the time, due to the lack of strict
-// ordering on the HashMap in `attributes`
 #[test]
 fn serialize_xml() {
     let data = "<catalog>
@@ -197,7 +201,8 @@ fn serialize_xml() {
         to_string(Xml::Element(
             Tag {
                 value: "catalog".into(),
-                attributes: HashMap::new(),
+                attributes: AttributeMap::default(),
+                spans: None,
             },
             Some(vec![Xml::Element(
                 Tag {
@@ -209,6 +214,7 @@ fn serialize_xml() {
                         ),
                         (String::from("product_image"), String::from("cardigan.jpg"))
                     ].into_iter().collect(),
+                    spans: None,
                 },
                 Some(vec![Xml::Element(
                     Tag {
@@ -216,19 +222,22 @@ fn serialize_xml() {
                         attributes: 
                             [(String::from("gender"), String::from("Mens")),
                         ].into_iter().collect(),
+                        spans: None,
                     },
                     Some(vec![
                         Xml::Element(
                             Tag {
                                 value: "item_number".into(),
-                                attributes: HashMap::new(),
+                                attributes: AttributeMap::default(),
+                                spans: None,
                             },
                             Some(vec![Xml::Text("QWZ5671".into(),),],),
                         ),
                         Xml::Element(
                             Tag {
                                 value: "price".into(),
-                                attributes: HashMap::new(),
+                                attributes: AttributeMap::default(),
+                                spans: None,
                             },
                             Some(vec![Xml::Text("39.95".into(),),],),
                         ),
@@ -236,7 +245,8 @@ fn serialize_xml() {
                     ],),
                 ),],),
             ),],),
-        )),
+        ))
+        .unwrap(),
     );
 }
 
@@ -249,7 +259,8 @@ fn parses_xml_ref() {
         Xml::Element(
             Tag {
                 value: "catalog".into(),
-                attributes: HashMap::new(),
+                attributes: AttributeMap::default(),
+                spans: None,
             },
             Some(vec![Xml::Element(
                 Tag {
@@ -261,6 +272,7 @@ fn parses_xml_ref() {
                         ),
                         (String::from("product_image"), String::from("cardigan.jpg"))
                     ].into_iter().collect(),
+                    spans: None,
                 },
                 Some(vec![Xml::Element(
                     Tag {
@@ -268,19 +280,22 @@ fn parses_xml_ref() {
                         attributes: 
                             [(String::from("gender"), String::from("Mens")),
                         ].into_iter().collect(),
+                        spans: None,
                     },
                     Some(vec![
                         Xml::Element(
                             Tag {
                                 value: "item_number".into(),
-                                attributes: HashMap::new(),
+                                attributes: AttributeMap::default(),
+                                spans: None,
                             },
                             Some(vec![Xml::Text("QWZ5671".into(),),],),
                         ),
                         Xml::Element(
                             Tag {
                                 value: "price".into(),
-                                attributes: HashMap::new(),
+                                attributes: AttributeMap::default(),
+                                spans: None,
                             },
                             Some(vec![Xml::Text("39.95".into(),),],),
                         ),
@@ -291,3 +306,1323 @@ fn parses_xml_ref() {
         ),
     );
 }
+
+#[test]
+fn parses_comments() {
+    let data = "<?xml version=\"1.0\"?>
+            <!-- Generated by hand -->
+            <config>
+                <!-- <disabled value=\"true\"/> -->
+                <enabled/>
+            </config>
+            <!-- trailing -->
+        ";
+
+    let parsed = Xml::from_input_str(data).unwrap();
+
+    assert_eq!(
+        parsed,
+        Xml::Element(
+            Tag {
+                value: "config".into(),
+                attributes: AttributeMap::default(),
+                spans: None,
+            },
+            Some(vec![
+                Xml::Comment(" <disabled value=\"true\"/> ".into()),
+                Xml::Element(
+                    Tag {
+                        value: "enabled".into(),
+                        attributes: AttributeMap::default(),
+                        spans: None,
+                    },
+                    None
+                ),
+            ],),
+        ),
+    );
+    assert_eq!(Xml::from_input_str(&to_string(parsed.clone()).unwrap()).unwrap(), parsed);
+
+    assert!(Xml::from_input_str("<a><!-- a -- b --></a>").is_err());
+}
+
+#[test]
+fn parses_cdata() {
+    let data = "<script><![CDATA[ if (a < b && c) { x(); } ]]></script>";
+
+    let parsed = Xml::from_input_str(data).unwrap();
+
+    assert_eq!(
+        parsed,
+        Xml::Element(
+            Tag {
+                value: "script".into(),
+                attributes: AttributeMap::default(),
+                spans: None,
+            },
+            Some(vec![Xml::CData(" if (a < b && c) { x(); } ".into())]),
+        ),
+    );
+    assert_eq!(
+        XmlRef::from_input_str(data).unwrap(),
+        XmlRef::Element(
+            TagRef {
+                value: "script".into(),
+                attributes: AttributeMap::default(),
+                spans: None,
+            },
+            Some(vec![XmlRef::CData(" if (a < b && c) { x(); } ")]),
+        ),
+    );
+    assert_eq!(Xml::from_input_str(&to_string(parsed.clone()).unwrap()).unwrap(), parsed);
+
+    let nested_terminator = Xml::Element(
+        Tag {
+            value: "sql".into(),
+            attributes: AttributeMap::default(),
+            spans: None,
+        },
+        Some(vec![Xml::CData("a]]>b".into())]),
+    );
+    assert_eq!(
+        Xml::from_input_str(&to_string(nested_terminator).unwrap()).unwrap(),
+        Xml::Element(
+            Tag {
+                value: "sql".into(),
+                attributes: AttributeMap::default(),
+                spans: None,
+            },
+            Some(vec![Xml::CData("a]]".into()), Xml::CData(">b".into())]),
+        ),
+    );
+}
+
+#[test]
+fn decodes_references() {
+    let data = "<formula title=\"&quot;less&quot; &#x26; &#62;\">a &lt; b &amp;&amp; c &#62; d</formula>";
+
+    assert_eq!(
+        Xml::from_input_str(data).unwrap(),
+        Xml::Element(
+            Tag {
+                value: "formula".into(),
+                attributes: [("title".into(), "\"less\" & >".into())].into_iter().collect(),
+                spans: None,
+            },
+            Some(vec![Xml::Text("a < b && c > d".into())]),
+        ),
+    );
+
+    match &XmlRef::from_input_str("<a b=\"plain\">no references</a>").unwrap() {
+        XmlRef::Element(TagRef { attributes, .. }, Some(children)) => {
+            assert!(matches!(attributes["b"], Cow::Borrowed("plain")));
+            assert!(matches!(children[..], [XmlRef::Text(Cow::Borrowed("no references"))]));
+        }
+        x => panic!("unexpected {x:?}"),
+    }
+
+    assert!(Xml::from_input_str("<a>&unknown;</a>").is_err());
+    assert!(Xml::from_input_str("<a>&#0;</a>").is_err());
+}
+
+#[test]
+fn serialize_escapes() {
+    let xml = Xml::Element(
+        Tag {
+            value: "note".into(),
+            attributes: [
+                ("title".into(), "Say \"hi\"".into()),
+                ("both".into(), "it's \"quoted\"\t<&>".into()),
+            ]
+            .into_iter()
+            .collect(),
+            spans: None,
+        },
+        Some(vec![Xml::Text("a < b && c > d".into())]),
+    );
+
+    let serialized = to_string(xml.clone()).unwrap();
+
+    assert!(serialized.contains("title=\"Say &quot;hi&quot;\""));
+    assert!(serialized.contains("a &lt; b &amp;&amp; c &gt; d"));
+    assert_eq!(Xml::from_input_str(&serialized).unwrap(), xml);
+
+    let tag = |name: &str| Tag {
+        value: name.into(),
+        attributes: AttributeMap::default(),
+        spans: None,
+    };
+    let element = |children| {
+        Xml::Element(tag("a"), Some(vec![Xml::Element(tag("a"), Some(children))]))
+    };
+    let xml = element(vec![
+        Xml::Text(" l1\n  l2\r\n\t".into()),
+        Xml::Comment(" c1\nc2 ".into()),
+        Xml::Element(tag("b"), None),
+        Xml::Text("\u{A0}".into()),
+        Xml::ProcessingInstruction {
+            target: "pi".into(),
+            data: "x\n y?".into(),
+        },
+    ]);
+    assert_eq!(Xml::from_input_str(&to_string(xml.clone()).unwrap()).unwrap(), xml);
+
+    let error = |x: Xml| to_string(element(vec![x])).unwrap_err();
+    let pi = |target: &str, data: &str| Xml::ProcessingInstruction {
+        target: target.into(),
+        data: data.into(),
+    };
+    assert_eq!(
+        error(Xml::Comment("x--y".into())),
+        SerializeError::Comment("x--y".into())
+    );
+    assert!(matches!(error(Xml::Comment("x-".into())), SerializeError::Comment(_)));
+    assert!(matches!(error(pi("pi", "a?>b")), SerializeError::ProcessingInstruction { .. }));
+    assert!(matches!(error(pi("pi", " a")), SerializeError::ProcessingInstruction { .. }));
+    assert!(matches!(error(pi("XML", "")), SerializeError::ProcessingInstruction { .. }));
+    let invalid = Xml::Element(tag("a b"), None);
+    assert_eq!(error(invalid), SerializeError::Name("a b".into()));
+}
+
+#[test]
+fn resolves_namespaces() {
+    let data = "
+            <svg:svg xmlns:svg=\"http://www.w3.org/2000/svg\" xmlns=\"urn:default\" xml:lang=\"en\">
+                <svg:rect width=\"10\"/>
+                <title/>
+                <plain xmlns=\"\"/>
+            </svg:svg>
+        ";
+
+    let resolved = Xml::from_input_str(data).unwrap().resolve_namespaces().unwrap();
+    assert!(resolved.tag_has_name("svg:svg"));
+    assert!(resolved.tag_has_name_ns("http://www.w3.org/2000/svg", "svg"));
+
+    let Xml::Element(_, Some(children)) = &resolved else {
+        panic!("unexpected {resolved:?}");
+    };
+    assert!(children[0].tag_has_name_ns("http://www.w3.org/2000/svg", "rect"));
+    assert!(children[1].tag_has_name_ns("urn:default", "title"));
+    assert!(children[2].tag_has_name("plain") && !children[2].tag_has_name_ns("urn:default", "plain"));
+
+    let resolved_ref = XmlRef::from_input_str(data).unwrap().resolve_namespaces().unwrap();
+    assert!(resolved_ref.tag_has_name_ns("http://www.w3.org/2000/svg", "svg"));
+
+    assert_eq!(
+        Xml::from_input_str("<a:b/>").unwrap().resolve_namespaces(),
+        Err(crate::namespace::NamespaceError { prefix: "a".into() })
+    );
+}
+
+#[test]
+fn parses_xml_names() {
+    let data = "<com.example.Config _id·x=\"1\" été=\"2\"><x-y.z_0/></com.example.Config>";
+
+    assert_eq!(
+        Xml::from_input_str(data).unwrap(),
+        Xml::Element(
+            Tag {
+                value: "com.example.Config".into(),
+                attributes: [("_id·x".into(), "1".into()), ("été".into(), "2".into())]
+                    .into_iter()
+                    .collect(),
+                spans: None,
+            },
+            Some(vec![Xml::Element(
+                Tag {
+                    value: "x-y.z_0".into(),
+                    attributes: AttributeMap::default(),
+                    spans: None,
+                },
+                None
+            )]),
+        ),
+    );
+
+    assert!(Xml::from_input_str("<1abc/>").is_err());
+    assert!(Xml::from_input_str("<-abc/>").is_err());
+    assert!(Xml::from_input_str("<.abc/>").is_err());
+    assert!(Xml::from_input_str("<a:/>").is_err());
+
+    let expected = crate::error::XmlErrorKind::Expected("name".into());
+    for (data, offset) in [("<a><-x/></a>", 4), ("<a>\n  < b/></a>", 7), ("<a>text<1/></a>", 8)] {
+        let e = Xml::from_input_str(data).unwrap_err();
+        assert_eq!((&e.kind, e.offset()), (&expected, offset), "{data}");
+        assert_eq!(XmlRef::from_input_str(data).unwrap_err(), e);
+        let events = crate::parse::Reader::new(data).find_map(Result::err).unwrap();
+        assert_eq!(events, e);
+    }
+
+    let expected = crate::error::XmlErrorKind::Expected("`>`".into());
+    for (data, offset) in [("<a><b/c></a>", 5), ("<a>\n  <b x='1'y='2'/></a>", 14)] {
+        let e = Xml::from_input_str(data).unwrap_err();
+        assert_eq!((&e.kind, e.offset()), (&expected, offset), "{data}");
+        assert_eq!(XmlRef::from_input_str(data).unwrap_err(), e);
+        let events = crate::parse::Reader::new(data).find_map(Result::err).unwrap();
+        assert_eq!(events, e);
+    }
+}
+
+#[test]
+fn reports_error_position() {
+    let data = "<catalog>\n    <price val=9.95/>\n</catalog>";
+
+    let e = Xml::from_input_str(data).unwrap_err();
+    assert_eq!((e.line(), e.column(), e.offset()), (2, 16, 25));
+    assert_eq!(
+        e.kind,
+        crate::error::XmlErrorKind::Expected("`\"` in attribute value".into())
+    );
+    assert_eq!(
+        e.to_string(),
+        "expected `\"` in attribute value at 2:16\n  |\n2 |     <price val=9.95/>\n  |                ^"
+    );
+
+    let line = format!("<a>{}<b c=d/>{}</a>", "é".repeat(1000), "x".repeat(1000));
+    let e = Xml::from_input_str(&line).unwrap_err();
+    assert_eq!((e.line(), e.column()), (1, 1009));
+    let message = e.to_string();
+    let lines: Vec<_> = message.lines().collect();
+    let window = format!("...{}<b c=d/>{}...", "é".repeat(55), "x".repeat(57));
+    assert_eq!(lines[2], format!("1 | {window}"));
+    assert_eq!(lines[3], format!("  | {}^", " ".repeat(63)));
+
+    let e = XmlRef::from_input_str("<catalog>\n    <price>\n        39.95\n").unwrap_err();
+    assert_eq!(
+        e.kind,
+        crate::error::XmlErrorKind::UnclosedElement {
+            name: "price".into(),
+            opened_at: crate::error::Position {
+                offset: 14,
+                line: 2,
+                column: 5
+            },
+        }
+    );
+    assert!(e
+        .to_string()
+        .starts_with("expected closing tag `</price>` for element opened at 2:5 at 4:1"));
+}
+
+#[test]
+fn reports_mismatched_tags() {
+    let data = "<catalog>\n    <product>\n        <price>39.95</product>\n</catalog>";
+    let mismatched = crate::error::XmlErrorKind::MismatchedTag {
+        expected: "price".into(),
+        found: "product".into(),
+        opened_at: crate::error::Position {
+            offset: 32,
+            line: 3,
+            column: 9,
+        },
+    };
+
+    let e = Xml::from_input_str(data).unwrap_err();
+    assert_eq!(e.kind, mismatched);
+    assert_eq!((e.line(), e.column()), (3, 21));
+    assert!(e.to_string().starts_with(
+        "expected closing tag `</price>` for element opened at 3:9, found `</product>` at 3:21"
+    ));
+
+    let e = XmlRef::from_input_str(data).unwrap_err();
+    assert_eq!(e.kind, mismatched);
+
+    let e = Xml::from_input_str("<a>\n    <b>\n").unwrap_err();
+    assert!(matches!(
+        e.kind,
+        crate::error::XmlErrorKind::UnclosedElement { ref name, .. } if name == "b"
+    ));
+}
+
+#[test]
+fn reads_events() {
+    let data = "<?xml version=\"1.0\"?>
+            <catalog>
+                <!-- prices in €uro -->
+                <price val=\"19.95\"/>
+                <?editor hint?>
+                <item>Café &amp; <![CDATA[<b>]]></item>
+            </catalog>
+        ";
+
+    let events = crate::parse::Reader::new(data)
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(
+        events,
+        vec![
+            EventRef::Declaration(XmlDeclaration::default()),
+            EventRef::StartElement(TagRef {
+                value: "catalog".into(),
+                attributes: AttributeMap::default(),
+                spans: None,
+            }),
+            EventRef::Comment(" prices in €uro "),
+            EventRef::StartElement(TagRef {
+                value: "price".into(),
+                attributes: [("val", "19.95".into())].into_iter().collect(),
+                spans: None,
+            }),
+            EventRef::EndElement("price".into(), None),
+            EventRef::ProcessingInstruction {
+                target: "editor",
+                data: "hint",
+            },
+            EventRef::StartElement(TagRef {
+                value: "item".into(),
+                attributes: AttributeMap::default(),
+                spans: None,
+            }),
+            EventRef::Text("Café &".into(), None),
+            EventRef::CData("<b>"),
+            EventRef::EndElement("item".into(), None),
+            EventRef::EndElement("catalog".into(), None),
+        ]
+    );
+
+    // A tiny buffer makes tokens (and UTF-8 sequences) straddle reads.
+    let streamed = crate::parse::StreamReader::new(std::io::BufReader::with_capacity(
+        3,
+        data.as_bytes(),
+    ))
+    .collect::<Result<Vec<_>, _>>()
+    .unwrap();
+    assert_eq!(
+        streamed,
+        events.into_iter().map(Event::from).collect::<Vec<_>>()
+    );
+
+    let e = crate::parse::StreamReader::new(std::io::BufReader::with_capacity(
+        3,
+        "<a>\n  <b></a>".as_bytes(),
+    ))
+    .find_map(Result::err)
+    .unwrap();
+    assert_eq!((e.line(), e.column()), (2, 6));
+    assert!(matches!(
+        e.kind,
+        crate::error::XmlErrorKind::MismatchedTag { ref expected, .. } if expected == "b"
+    ));
+}
+
+#[test]
+fn parses_with_handler() {
+    use crate::{error::XmlError, parse::Handler};
+
+    #[derive(Default)]
+    struct PriceTotal {
+        in_price: bool,
+        prices: usize,
+        total: f64,
+        errors: usize,
+    }
+
+    impl<'a> Handler<'a> for PriceTotal {
+        fn start_element(&mut self, tag: TagRef<'a>) {
+            self.in_price = tag.value == "price";
+        }
+
+        fn end_element(&mut self, _: QNameRef<'a>) {
+            self.in_price = false;
+        }
+
+        fn text(&mut self, text: Cow<'a, str>) {
+            if self.in_price {
+                self.prices += 1;
+                self.total += text.parse::<f64>().unwrap();
+            }
+        }
+
+        fn error(&mut self, _: &XmlError) {
+            self.errors += 1;
+        }
+    }
+
+    let mut handler = PriceTotal::default();
+    crate::parse::parse_with_handler(
+        "<prices><price>1.5</price><price>2.5</price><other>7</other></prices>",
+        &mut handler,
+    )
+    .unwrap();
+    assert_eq!((handler.prices, handler.total, handler.errors), (2, 4.0, 0));
+
+    let mut handler = PriceTotal::default();
+    assert!(crate::parse::parse_with_handler("<prices><price>1.5</prices>", &mut handler).is_err());
+    assert_eq!((handler.prices, handler.errors), (1, 1));
+}
+
+#[test]
+fn parses_declaration() {
+    let data = "<?xml version=\"1.1\" encoding='UTF-8' standalone=\"yes\" ?>\n<a/>";
+
+    let document = Document::parse(data).unwrap();
+    assert_eq!(
+        document.declaration,
+        Some(XmlDeclaration {
+            version: "1.1".into(),
+            encoding: Some("UTF-8".into()),
+            standalone: Some(true),
+        })
+    );
+    assert_eq!(
+        document_to_string(document.clone()).unwrap(),
+        "<?xml version=\"1.1\" encoding=\"UTF-8\" standalone=\"yes\"?>\n<a/>"
+    );
+    assert_eq!(Document::parse(&document_to_string(document.clone()).unwrap()).unwrap(), document);
+
+    assert_eq!(Document::parse("<a/>").unwrap().declaration, None);
+
+    let e = Document::parse("<!-- first -->\n<?xml version=\"1.0\"?><a/>").unwrap_err();
+    assert_eq!(e.kind, crate::error::XmlErrorKind::MisplacedDeclaration);
+    assert_eq!((e.line(), e.column()), (2, 1));
+
+    let e = Document::parse("\n <?xml version=\"1.0\"?><a/>").unwrap_err();
+    assert_eq!(e.kind, crate::error::XmlErrorKind::MisplacedDeclaration);
+    assert_eq!((e.line(), e.column()), (2, 2));
+    let e = crate::parse::Reader::new(" <?xml version=\"1.0\"?><a/>")
+        .find_map(Result::err)
+        .unwrap();
+    assert_eq!(e.kind, crate::error::XmlErrorKind::MisplacedDeclaration);
+
+    let e = Document::parse("<?xml version=\"2.0\"?><a/>").unwrap_err();
+    assert_eq!(e.kind, crate::error::XmlErrorKind::Expected("version".into()));
+}
+
+#[test]
+fn parses_processing_instructions() {
+    let data = "<page><?php echo $title; ?><br/><?oxygen?></page><?done?>";
+
+    let parsed = Xml::from_input_str(data).unwrap();
+    assert_eq!(
+        parsed,
+        Xml::Element(
+            Tag {
+                value: "page".into(),
+                attributes: AttributeMap::default(),
+                spans: None,
+            },
+            Some(vec![
+                Xml::ProcessingInstruction {
+                    target: "php".into(),
+                    data: "echo $title; ".into(),
+                },
+                Xml::Element(
+                    Tag {
+                        value: "br".into(),
+                        attributes: AttributeMap::default(),
+                        spans: None,
+                    },
+                    None
+                ),
+                Xml::ProcessingInstruction {
+                    target: "oxygen".into(),
+                    data: "".into(),
+                },
+            ]),
+        ),
+    );
+    assert_eq!(Xml::from_input_str(&to_string(parsed.clone()).unwrap()).unwrap(), parsed);
+
+    assert!(matches!(
+        &XmlRef::from_input_str(data).unwrap(),
+        XmlRef::Element(_, Some(children)) if children[0] == XmlRef::ProcessingInstruction {
+            target: "php",
+            data: "echo $title; ",
+        }
+    ));
+
+    assert_eq!(
+        Xml::from_input_str("<a><?xml version=\"1.0\"?></a>").unwrap_err().kind,
+        crate::error::XmlErrorKind::MisplacedDeclaration
+    );
+}
+
+#[test]
+fn parses_document() {
+    let data = "<?xml version=\"1.0\"?>
+<!-- generated -->
+<!DOCTYPE note SYSTEM \"note.dtd\">
+<?xml-stylesheet href=\"note.xsl\"?>
+<note/>
+<!-- end -->
+";
+
+    let document = Document::parse(data).unwrap();
+    assert_eq!(
+        document,
+        Document {
+            declaration: Some(XmlDeclaration::default()),
+            before_doctype: vec![Misc::Comment(" generated ".into())],
+            doctype: Some(Doctype {
+                name: "note".into(),
+                external_id: Some(ExternalId::System("note.dtd".into())),
+                internal_subset: vec![],
+            }),
+            prolog: vec![
+                Misc::ProcessingInstruction {
+                    target: "xml-stylesheet".into(),
+                    data: "href=\"note.xsl\"".into(),
+                },
+            ],
+            root: Xml::Element(
+                Tag {
+                    value: "note".into(),
+                    attributes: AttributeMap::default(),
+                    spans: None,
+                },
+                None
+            ),
+            epilog: vec![Misc::Comment(" end ".into())],
+        }
+    );
+    assert_eq!(Document::parse(&document_to_string(document.clone()).unwrap()).unwrap(), document);
+
+    for data in ["<!-- lic -->\n<!DOCTYPE a>\n<a/>", "<!-- lic -->\n<a/>"] {
+        let document = Document::parse(data).unwrap();
+        assert_eq!(document_to_string(document).unwrap(), data);
+    }
+
+    let e = Document::parse("<note/>\n<other/>").unwrap_err();
+    assert_eq!((e.line(), e.column()), (2, 1));
+    assert!(Xml::from_input_str("<note/> trailing").is_err());
+    assert!(XmlRef::from_input_str("<note/></note>").is_err());
+
+    use crate::{error::XmlErrorKind, parse::Reader};
+    let unclosed = XmlErrorKind::UnclosedElement {
+        name: "a".into(),
+        opened_at: crate::error::Position::START,
+    };
+    let expected = |what: &str| XmlErrorKind::Expected(what.into());
+    for (data, offset, kind) in [
+        ("<!DOCTYPE a><!DOCTYPE b><a/>", 12, expected("root element")),
+        ("<a><!DOCTYPE b></a>", 3, unclosed),
+        ("<a/><!DOCTYPE b>", 4, expected("end of input after the root element")),
+    ] {
+        assert!(Document::parse(data).is_err(), "{data}");
+        let e = Reader::new(data).find_map(Result::err).unwrap();
+        assert_eq!((&e.kind, e.offset()), (&kind, offset), "{data}");
+    }
+}
+
+#[test]
+fn parses_doctype() {
+    let data = r#"<!DOCTYPE book PUBLIC "-//OASIS//DTD DocBook XML V4.5//EN" "docbook>4.5.dtd" [
+  <!ELEMENT book (title, (chapter | appendix)+)>
+  <!ELEMENT title (#PCDATA | emphasis)*>
+  <!ATTLIST book
+      id ID #IMPLIED
+      status (draft | final) "draft"
+      version CDATA #FIXED '4.5'>
+  <!ENTITY % local SYSTEM "local.ent">
+  %local;
+  <!ENTITY product "Widget &amp; Co">
+  <!ENTITY logo SYSTEM "logo.png" NDATA png>
+  <!NOTATION png PUBLIC "image/png">
+  <!-- declarations end -->
+]>"#;
+
+    let (rest, doctype) = crate::parse::doc_type::<()>(data).unwrap();
+    assert_eq!(rest, "");
+    assert_eq!(doctype.name, "book");
+    assert_eq!(
+        doctype.external_id,
+        Some(ExternalId::Public {
+            public_id: "-//OASIS//DTD DocBook XML V4.5//EN".into(),
+            system_id: Some("docbook>4.5.dtd".into()),
+        })
+    );
+    assert_eq!(
+        doctype.internal_subset[0],
+        MarkupDecl::Element {
+            name: "book".into(),
+            content: ContentSpec::Children(ContentParticle {
+                particle: Particle::Sequence(vec![
+                    ContentParticle {
+                        particle: Particle::Name("title".into()),
+                        repetition: Repetition::One,
+                    },
+                    ContentParticle {
+                        particle: Particle::Choice(vec![
+                            ContentParticle {
+                                particle: Particle::Name("chapter".into()),
+                                repetition: Repetition::One,
+                            },
+                            ContentParticle {
+                                particle: Particle::Name("appendix".into()),
+                                repetition: Repetition::One,
+                            },
+                        ]),
+                        repetition: Repetition::OneOrMore,
+                    },
+                ]),
+                repetition: Repetition::One,
+            }),
+        }
+    );
+    assert_eq!(
+        doctype.internal_subset[1],
+        MarkupDecl::Element {
+            name: "title".into(),
+            content: ContentSpec::Mixed(vec!["emphasis".into()]),
+        }
+    );
+    assert_eq!(
+        doctype.attributes_of("book").map(|a| &a.default).collect::<Vec<_>>(),
+        [
+            &DefaultDecl::Implied,
+            &DefaultDecl::Value("draft".into()),
+            &DefaultDecl::Fixed("4.5".into()),
+        ]
+    );
+    assert_eq!(
+        doctype.internal_subset[4],
+        MarkupDecl::ParameterEntityReference("local".into())
+    );
+    assert_eq!(
+        doctype.entity("product"),
+        Some(&EntityValue::Internal("Widget &amp; Co".into()))
+    );
+    assert_eq!(
+        doctype.entity("logo"),
+        Some(&EntityValue::External {
+            id: ExternalId::System("logo.png".into()),
+            notation: Some("png".into()),
+        })
+    );
+    assert_eq!(doctype.entity("local"), None);
+    assert_eq!(doctype.internal_subset.len(), 9);
+
+    let serialized = format!("{}<root/>", doctype_to_string(&doctype).unwrap());
+    assert_eq!(Document::parse(&serialized).unwrap().doctype, Some(doctype));
+
+    assert!(crate::parse::doc_type::<()>("<!DOCTYPE note PUBLIC \"id\">").is_err());
+    assert!(Document::parse("<!DOCTYPE note [<!ELEMENT note (a | b, c)>]><note/>").is_err());
+    for model in ["((a)", "(a, (b | ))", "(a b)", "()"] {
+        let data = format!("<!DOCTYPE note [<!ELEMENT note {model}>]><note/>");
+        assert!(Document::parse(&data).is_err(), "{model}");
+    }
+    let data = "<!DOCTYPE note [<!ELEMENT note ( (a?) , (b|c)* )+>]><note/>";
+    let Some(MarkupDecl::Element { content, .. }) = Document::parse(data)
+        .unwrap()
+        .doctype
+        .and_then(|d| d.internal_subset.into_iter().next())
+    else {
+        panic!("expected an element declaration")
+    };
+    let name = |n: &str, repetition| ContentParticle {
+        particle: Particle::Name(n.into()),
+        repetition,
+    };
+    let b_or_c = Particle::Choice(vec![name("b", Repetition::One), name("c", Repetition::One)]);
+    let expected = ContentParticle {
+        particle: Particle::Sequence(vec![
+            ContentParticle {
+                particle: Particle::Sequence(vec![name("a", Repetition::Optional)]),
+                repetition: Repetition::One,
+            },
+            ContentParticle {
+                particle: b_or_c,
+                repetition: Repetition::ZeroOrMore,
+            },
+        ]),
+        repetition: Repetition::OneOrMore,
+    };
+    assert_eq!(content, ContentSpec::Children(expected));
+}
+
+#[test]
+fn expands_entities() {
+    use crate::{
+        error::{EntityError, Limit, XmlErrorKind},
+        parse::{ParseOptions, Reader},
+    };
+
+    let data = r#"<!DOCTYPE note [
+  <!ENTITY company "Widget &amp; Co">
+  <!ENTITY signature "-- &company;">
+  <!ENTITY company "ignored">
+]>
+<note from="&company;">Thanks, &signature; &#33;</note>"#;
+
+    let document = Document::parse(data).unwrap();
+    let Xml::Element(tag, Some(children)) = &document.root else {
+        panic!("expected an element, got {:?}", document.root);
+    };
+    assert_eq!(tag.attributes["from"], "Widget & Co");
+    assert_eq!(children, &[Xml::Text("Thanks, -- Widget & Co !".into())]);
+
+    let texts = Reader::new(data)
+        .filter_map(|e| match e.unwrap() {
+            EventRef::Text(t, _) => Some(t),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(texts, ["Thanks, -- Widget & Co !"]);
+
+    let kind = |data: &str, options| Document::parse_with(data, options).unwrap_err().kind;
+    assert_eq!(
+        kind("<note>&company;</note>", ParseOptions::default()),
+        XmlErrorKind::Entity {
+            name: "company".into(),
+            error: EntityError::Undefined,
+        }
+    );
+    assert_eq!(
+        kind(
+            "<!DOCTYPE a [<!ENTITY a \"&b;\"><!ENTITY b \"&a;\">]><a>&a;</a>",
+            ParseOptions::default()
+        ),
+        XmlErrorKind::Entity {
+            name: "a".into(),
+            error: EntityError::Recursive,
+        }
+    );
+    assert_eq!(
+        kind(
+            "<!DOCTYPE a [<!ENTITY a \"<b/>\">]><a t='&a;'/>",
+            ParseOptions::default()
+        ),
+        XmlErrorKind::Entity {
+            name: "a".into(),
+            error: EntityError::Markup,
+        }
+    );
+
+    let mut laughs = String::from("<!DOCTYPE lolz [\n<!ENTITY lol0 \"lol\">\n");
+    for n in 1..10 {
+        let previous = format!("&lol{};", n - 1).repeat(10);
+        laughs += &format!("<!ENTITY lol{n} \"{previous}\">\n");
+    }
+    laughs += "]>\n<lolz>&lol9;</lolz>";
+
+    let e = Document::parse(&laughs).unwrap_err();
+    assert_eq!(
+        e.kind,
+        XmlErrorKind::LimitExceeded(Limit::EntityExpansion(
+            ParseOptions::default().max_entity_expansion
+        ))
+    );
+    assert_eq!((e.line(), e.column()), (13, 7));
+
+    let shallow = ParseOptions {
+        max_entity_depth: 4,
+        ..ParseOptions::default()
+    };
+    assert_eq!(
+        kind(&laughs, shallow),
+        XmlErrorKind::LimitExceeded(Limit::EntityDepth(4))
+    );
+
+    // Text that is parsed again as more input is streamed only counts once.
+    let data = format!(
+        "<!DOCTYPE a [<!ENTITY e '{}'>]><a>{}</a>",
+        "x".repeat(100),
+        "&e;".repeat(5000)
+    );
+    let exact = ParseOptions {
+        max_entity_expansion: 100 * 5000,
+        ..ParseOptions::default()
+    };
+    assert!(Reader::with_options(&data, exact).all(|e| e.is_ok()));
+    for capacity in [1024, 1025, 1026] {
+        let input = std::io::BufReader::with_capacity(capacity, data.as_bytes());
+        let mut events = crate::parse::StreamReader::with_options(input, exact);
+        assert!(events.all(|e| e.is_ok()), "{capacity}");
+    }
+}
+
+#[test]
+fn enforces_limits() {
+    use crate::{
+        error::{Limit, XmlErrorKind},
+        parse::{ParseOptions, Reader},
+    };
+
+    let limits = ParseOptions {
+        max_depth: 3,
+        max_attributes_per_element: 2,
+        max_name_length: 8,
+        max_text_length: 16,
+        max_total_nodes: 8,
+        ..ParseOptions::default()
+    };
+    let limit = |data: &str| {
+        let tree = Xml::from_input_str_with(data, limits).unwrap_err();
+        let events = Reader::with_options(data, limits)
+            .find_map(Result::err)
+            .unwrap();
+        assert_eq!(tree, events);
+
+        let XmlErrorKind::LimitExceeded(limit) = tree.kind else {
+            panic!("expected a limit to be exceeded, got {tree}");
+        };
+        (limit, tree.column())
+    };
+
+    assert!(Xml::from_input_str_with("<a><b><c a='1' b='2'>text</c></b></a>", limits).is_ok());
+    assert_eq!(limit("<a><b><c><d/></c></b></a>"), (Limit::Depth(3), 10));
+    assert_eq!(limit("<a x='1' y='2' z='3'/>"), (Limit::AttributesPerElement(2), 4));
+    let many = format!("<a{}/>", " x='1'".repeat(100_000));
+    assert_eq!(limit(&many), (Limit::AttributesPerElement(2), 4));
+    assert_eq!(limit("<a><abcdefghi/></a>"), (Limit::NameLength(8), 5));
+    assert_eq!(limit("<a abcdefghi='1'/>"), (Limit::NameLength(8), 4));
+    assert_eq!(limit("<a> a rather long text </a>"), (Limit::TextLength(16), 5));
+    assert_eq!(limit("<a><![CDATA[a rather long text]]></a>"), (Limit::TextLength(16), 4));
+    assert_eq!(
+        limit("<a><b/><b/><b/><b/><!-- c --><b/><b/><b/></a>"),
+        (Limit::TotalNodes(8), 38)
+    );
+    assert_eq!(limit("<!DOCTYPE a [<!ELEMENT a ((((b))))>]><a/>"), (Limit::Depth(3), 29));
+
+    let model = "(".repeat(100_000) + "b" + &")".repeat(100_000);
+    let data = format!("<!DOCTYPE a [<!ELEMENT a {model}>]><a/>");
+    let options = ParseOptions {
+        max_depth: 64,
+        ..ParseOptions::default()
+    };
+    let e = Document::parse_with(&data, options).unwrap_err();
+    assert_eq!(e.kind, XmlErrorKind::LimitExceeded(Limit::Depth(64)));
+}
+
+#[test]
+fn parses_deep_documents() {
+    let data = "<a>".repeat(50_000) + "text" + &"</a>".repeat(50_000);
+
+    let xml = Xml::from_input_str(&data).unwrap();
+    let mut depth = 0;
+    let mut x = &xml;
+    while let Xml::Element(_, Some(children)) = x {
+        depth += 1;
+        x = &children[0];
+    }
+    assert_eq!(depth, 50_000);
+    assert_eq!(x, &Xml::Text("text".into()));
+
+    assert_eq!(xml.clone(), xml);
+    assert_eq!(Xml::from_input_str(&to_string(xml.clone()).unwrap()).unwrap(), xml);
+    assert!(xml.resolve_namespaces().is_ok());
+
+    let xml = XmlRef::from_input_str(&data).unwrap();
+    assert_eq!(xml.clone(), xml);
+    assert!(xml.resolve_namespaces().is_ok());
+
+    let cst = CstDocument::parse(&data).unwrap();
+    assert_eq!(cst.clone(), cst);
+    assert_eq!(cst_to_string(&cst), data);
+    let mut other = cst.clone();
+    other.root.elements_mut().next().unwrap().name.push('b');
+    assert_ne!(other, cst);
+
+    let e = Xml::from_input_str(&data[..data.len() - 4]).unwrap_err();
+    assert!(matches!(
+        e.kind,
+        crate::error::XmlErrorKind::UnclosedElement { opened_at, .. } if opened_at.column == 1
+    ));
+
+    let model = "(".repeat(200_000) + "b | (c, d)" + &")*".repeat(200_000);
+    let data = format!("<!DOCTYPE a [<!ELEMENT a {model}>]><a/>");
+    assert!(Xml::from_input_str(&data).is_ok());
+    let (_, doctype) = crate::parse::doc_type::<()>(&data[..data.len() - 4]).unwrap();
+    assert_eq!(doctype.clone(), doctype);
+    let document = Document::parse(&data).unwrap();
+    assert_eq!(document.doctype.as_ref(), Some(&doctype));
+    let serialized = document_to_string(document.clone()).unwrap();
+    assert_eq!(Document::parse(&serialized).unwrap(), document);
+}
+
+#[test]
+fn decodes_bytes() {
+    use crate::{
+        encoding::{decode, Encoding},
+        error::XmlErrorKind,
+    };
+
+    let utf16 = |s: &str, bom: &[u8], be: bool| -> Vec<u8> {
+        let units = s.encode_utf16();
+        let bytes = units.flat_map(|u| if be { u.to_be_bytes() } else { u.to_le_bytes() });
+        bom.iter().copied().chain(bytes).collect()
+    };
+    let expected = Xml::from_input_str("<a b=\"é\">€ ü</a>").unwrap();
+
+    let le = utf16("<a b=\"é\">€ ü</a>", &[0xFF, 0xFE], false);
+    assert_eq!(Encoding::detect(&le).unwrap(), (Encoding::Utf16Le, 2));
+    assert_eq!(Xml::from_bytes(&le).unwrap(), expected);
+    let be = utf16("<?xml version=\"1.0\"?><a b=\"é\">€ ü</a>", &[], true);
+    assert_eq!(Encoding::detect(&be).unwrap(), (Encoding::Utf16Be, 0));
+    assert_eq!(Document::from_bytes(&be).unwrap().root, expected);
+
+    let utf8 = b"\xEF\xBB\xBF<a b=\"\xC3\xA9\">\xE2\x82\xAC \xC3\xBC</a>";
+    assert!(matches!(decode(utf8).unwrap(), Cow::Borrowed(s) if s.starts_with('<')));
+    assert_eq!(Xml::from_reader(&utf8[..]).unwrap(), expected);
+
+    let cp1252 = b"<?xml version=\"1.0\" encoding=\"Windows-1252\"?><a b=\"\xE9\">\x80 \xFC</a>";
+    let document = Document::from_bytes(cp1252).unwrap();
+    assert_eq!(document.root, expected);
+    assert_eq!(document.declaration.as_ref().unwrap().encoding.as_deref(), Some("UTF-8"));
+    let serialized = document_to_string(document.clone()).unwrap();
+    assert_eq!(Document::from_bytes(serialized.as_bytes()).unwrap(), document);
+    let latin1 = b"<?xml version='1.0' encoding='ISO-8859-1'?><a>\xFC</a>";
+    assert_eq!(decode(latin1).unwrap(), "<?xml version='1.0' encoding='ISO-8859-1'?><a>ü</a>");
+
+    let e = Xml::from_bytes(b"<?xml version='1.0' encoding='EBCDIC'?><a/>").unwrap_err();
+    assert_eq!(e.kind.to_string(), "cannot decode input: unsupported encoding `EBCDIC`");
+    let e = Xml::from_bytes(b"<a>\n  ok \xFF</a>").unwrap_err();
+    assert_eq!((e.line(), e.column()), (2, 6));
+    assert!(matches!(Xml::from_file("missing.xml").unwrap_err().kind, XmlErrorKind::Io(_)));
+}
+
+#[test]
+fn parses_quoted_attribute_values() {
+    let attribute = |i: &str, key: &str| match &Xml::from_input_str(i).unwrap() {
+        Xml::Element(tag, _) => tag.attributes[key].clone(),
+        _ => unreachable!(),
+    };
+
+    assert_eq!(attribute(r#"<a title="Don't"/>"#, "title"), "Don't");
+    assert_eq!(attribute(r#"<a title='say "hi"'/>"#, "title"), "say \"hi\"");
+    assert_eq!(attribute(r#"<a path="C:\new\" b='\'/>"#, "path"), r"C:\new\");
+    assert_eq!(attribute(r#"<a path="C:\new\" b='\'/>"#, "b"), r"\");
+    assert_eq!(attribute(r#"<a b="&quot;'&apos;"/>"#, "b"), "\"''");
+
+    assert!(Xml::from_input_str(r#"<a b="x'/>"#).is_err());
+    assert!(Xml::from_input_str(r#"<a b='x"/>"#).is_err());
+    assert!(XmlRef::from_input_str(r#"<a b="x'/>"#).is_err());
+
+    let xml = Xml::from_input_str(r#"<a b='say "hi", don&apos;t' c="C:\n"/>"#).unwrap();
+    assert_eq!(Xml::from_input_str(&to_string(xml.clone()).unwrap()).unwrap(), xml);
+}
+
+#[test]
+fn normalizes_attribute_values() {
+    use crate::parse::{ParseOptions, Reader};
+
+    let data = "<!DOCTYPE a [\n  <!ATTLIST a id ID #IMPLIED tokens NMTOKENS #IMPLIED>\n  \
+                <!ENTITY tab '&#9;\tx'>\n]>\n\
+                <a id='  x\t' tokens=' x\r\n  y&#32; ' text=' x\r\ny&#10;z&tab; '/>";
+    let attributes = |options| match &Document::parse_with(data, options).unwrap().root {
+        Xml::Element(tag, _) => {
+            let mut attributes: Vec<_> = tag.attributes.clone().into_iter().collect();
+            attributes.sort();
+            attributes
+        }
+        _ => unreachable!(),
+    };
+
+    let normalized = attributes(ParseOptions::default());
+    assert_eq!(
+        normalized,
+        [
+            ("id".to_string(), "x".to_string()),
+            ("text".into(), " x y\nz  x ".into()),
+            ("tokens".into(), "x y".into()),
+        ]
+    );
+
+    let options = ParseOptions {
+        normalize_attributes: false,
+        ..ParseOptions::default()
+    };
+    assert_eq!(
+        attributes(options),
+        [
+            ("id".to_string(), "  x\t".to_string()),
+            ("text".into(), " x\r\ny\nz\t\tx ".into()),
+            ("tokens".into(), " x\r\n  y  ".into()),
+        ]
+    );
+    let xml = XmlRef::from_input_str_with("<a b='x\ny'/>", options).unwrap();
+    assert!(matches!(&xml, XmlRef::Element(tag, _) if matches!(tag.attributes["b"], Cow::Borrowed(_))));
+
+    let start = Reader::new(data).find_map(|e| match e.unwrap() {
+        EventRef::StartElement(tag) => Some(tag),
+        _ => None,
+    });
+    assert_eq!(start.unwrap().attributes["tokens"], "x y");
+}
+
+#[test]
+fn handles_whitespace() {
+    use crate::parse::{ParseOptions, Reader, StreamReader, Whitespace};
+
+    let data = "<p>\n  <b>a</b> <i>b  c</i>\n  \
+                <pre xml:space='preserve'> x\n <q xml:space='default'> y </q></pre>\n</p>";
+    let texts = |data: &str, whitespace| {
+        let options = ParseOptions {
+            whitespace,
+            ..ParseOptions::default()
+        };
+        let texts: Vec<String> = Reader::with_options(data, options)
+            .filter_map(|e| match e.unwrap() {
+                EventRef::Text(t, _) => Some(t.into_owned()),
+                _ => None,
+            })
+            .collect();
+        let streamed: Vec<String> = StreamReader::with_options(data.as_bytes(), options)
+            .filter_map(|e| match e.unwrap() {
+                Event::Text(t, _) => Some(t),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(streamed, texts);
+
+        let mut tree = Vec::new();
+        let mut pending = vec![XmlRef::from_input_str_with(data, options).unwrap()];
+        while let Some(x) = pending.pop() {
+            match &x {
+                XmlRef::Text(t) => tree.push(t.to_string()),
+                XmlRef::Element(_, Some(children)) => {
+                    pending.extend(children.iter().rev().cloned())
+                }
+                _ => (),
+            }
+        }
+        assert_eq!(tree, texts);
+        texts
+    };
+
+    assert_eq!(texts(data, Whitespace::Trim), ["a", "b  c", " x\n ", "y"]);
+    assert_eq!(texts(data, Whitespace::Collapse), ["a", "b c", " x\n ", "y"]);
+    assert_eq!(
+        texts(data, Whitespace::Preserve),
+        ["\n  ", "a", " ", "b  c", "\n  ", " x\n ", " y ", "\n"]
+    );
+
+    // Only XML whitespace is trimmed, and text that ends up empty is dropped.
+    let data = "<!DOCTYPE a [<!ENTITY e ''>]><a><n>\u{A0}x\u{A0}</n><e>&e;</e><e> &e; </e></a>";
+    assert_eq!(texts(data, Whitespace::Trim), ["\u{A0}x\u{A0}"]);
+    assert_eq!(texts(data, Whitespace::Collapse), ["\u{A0}x\u{A0}"]);
+    assert_eq!(texts(data, Whitespace::Preserve), ["\u{A0}x\u{A0}", "  "]);
+
+    let xml = Xml::from_input_str("<a>\u{A0} x&#9;</a>").unwrap();
+    let serialized = to_string(xml.clone()).unwrap();
+    assert_eq!(serialized, "<a>\n    \u{A0} x&#9;\n</a>");
+    assert_eq!(Xml::from_input_str(&serialized).unwrap(), xml);
+}
+
+#[test]
+fn rejects_duplicate_attributes() {
+    use crate::{
+        error::XmlErrorKind,
+        parse::{DuplicateAttributes, ParseOptions, Reader},
+    };
+
+    let data = "<a>\n  <b x='1' y='2' x='3'/>\n</a>";
+    let duplicate = XmlErrorKind::DuplicateAttribute("x".into());
+
+    let e = Xml::from_input_str(data).unwrap_err();
+    assert_eq!((&e.kind, e.line(), e.column()), (&duplicate, 2, 18));
+    let e = XmlRef::from_input_str(data).unwrap_err();
+    assert_eq!((&e.kind, e.line(), e.column()), (&duplicate, 2, 18));
+    let e = Reader::new(data).find_map(Result::err).unwrap();
+    assert_eq!((&e.kind, e.line(), e.column()), (&duplicate, 2, 18));
+
+    let x = |duplicate_attributes| {
+        let options = ParseOptions {
+            duplicate_attributes,
+            ..ParseOptions::default()
+        };
+        match &XmlRef::from_input_str_with("<b x='1' y='2' x='3'/>", options).unwrap() {
+            XmlRef::Element(tag, _) => (tag.attributes["x"].to_string(), tag.attributes.len()),
+            _ => unreachable!(),
+        }
+    };
+    assert_eq!(x(DuplicateAttributes::KeepFirst), ("1".into(), 2));
+    assert_eq!(x(DuplicateAttributes::KeepLast), ("3".into(), 2));
+
+    // Past a handful of attributes, they are looked up by name instead.
+    let many: String = (0..20).map(|n| format!(" a{n}='{n}'")).collect();
+    let data = format!("<b{many} a2='x' a15='y'/>");
+    let options = ParseOptions {
+        duplicate_attributes: DuplicateAttributes::KeepLast,
+        ..ParseOptions::default()
+    };
+    match &XmlRef::from_input_str_with(&data, options).unwrap() {
+        XmlRef::Element(tag, _) => {
+            assert_eq!(tag.attributes.len(), 20);
+            assert_eq!((&tag.attributes["a2"], &tag.attributes["a15"]), (&"x".into(), &"y".into()));
+        }
+        _ => unreachable!(),
+    }
+    for duplicate in ["a2", "a15"] {
+        let data = format!("<b{many} {duplicate}='x'/>");
+        let e = Xml::from_input_str(&data).unwrap_err();
+        let expected = XmlErrorKind::DuplicateAttribute(duplicate.into());
+        assert_eq!((&e.kind, e.offset()), (&expected, many.len() + 3));
+    }
+}
+
+#[test]
+fn keeps_attribute_order() {
+    let data = "<a z=\"1\" b=\"2\" y=\"3\" a=\"4\" x=\"5\">\n    <b d=\"1\" c=\"2\"/>\n</a>";
+
+    assert_eq!(to_string(Xml::from_input_str(data).unwrap()).unwrap(), data);
+    let keys = match &XmlRef::from_input_str(data).unwrap() {
+        XmlRef::Element(tag, _) => tag.attributes.keys().copied().collect::<Vec<_>>(),
+        _ => unreachable!(),
+    };
+    assert_eq!(keys, ["z", "b", "y", "a", "x"]);
+
+    let mut tag = Tag {
+        value: "c".into(),
+        attributes: AttributeMap::default(),
+        spans: None,
+    };
+    tag.attributes.insert("second".into(), "2".into());
+    tag.attributes.insert("first".into(), "1".into());
+    assert_eq!(to_string(Xml::Element(tag, None)).unwrap(), r#"<c second="2" first="1"/>"#);
+}
+
+#[test]
+fn round_trips_losslessly() {
+    let data = "<?xml version='1.0'   encoding=\"UTF-8\"?>\n\
+                <!DOCTYPE config [ <!ENTITY me 'Me &#38;#38; I'> ]>\n<!-- prolog -->\n\
+                <config\tversion = '2'  name=\"a &amp; b\" >\n\
+                \x20 <empty/><empty ></empty>\r\n\
+                \x20 <entry key='x' value=\"&me; &#x41;\"\n    /><?pi data ?>\n\
+                \x20 <![CDATA[ <raw> ]]>text &lt; more</config  >\n<!-- epilog -->\n";
+
+    let mut cst = CstDocument::parse(data).unwrap();
+    assert_eq!(cst_to_string(&cst), data);
+    let empty: Vec<_> = cst.root.elements().map(|e| e.children.is_some()).collect();
+    assert_eq!(empty, [false, true, false]);
+    assert_eq!(cst.root.attribute("version").unwrap().raw_value, "2");
+
+    let entry = cst.root.elements_mut().nth(2).unwrap();
+    entry.set_attribute("value", "\"new\" & <improved>");
+    assert_eq!(
+        cst_to_string(&cst),
+        data.replace("&me; &#x41;", "&quot;new&quot; &amp; &lt;improved>")
+    );
+
+    cst.root.set_attribute("version", "3");
+    cst.root.remove_attribute("name");
+    cst.root.set_attribute("added", "yes");
+    assert!(cst_to_string(&cst).contains("<config\tversion = '3' added=\"yes\" >\n"));
+
+    assert!(CstDocument::parse("<a><b></a>").is_err());
+    assert!(CstDocument::parse("<a>&undefined;</a>").is_err());
+}
+
+#[test]
+fn records_spans() {
+    use crate::{
+        error::Position,
+        parse::{ParseOptions, Reader, StreamReader},
+    };
+    use std::io::BufReader;
+
+    let data = "<?xml version='1.0'?>\n<a x=\"1\">\n  <b y = 'é' z='2'/>text &amp; more\n</a >";
+    let options = ParseOptions {
+        spans: true,
+        ..ParseOptions::default()
+    };
+    let text = |span: Span| &data[span.range()];
+
+    let root = Xml::from_input_str_with(data, options).unwrap();
+    let Xml::Element(a, Some(children)) = &root else {
+        panic!("expected an element with children")
+    };
+    let spans = a.spans.as_deref().unwrap();
+    assert_eq!(text(spans.opening), "<a x=\"1\">");
+    assert_eq!(text(spans.closing.unwrap()), "</a >");
+    assert_eq!(text(spans.element()), &data[22..]);
+    assert_eq!(text(spans.attributes[0].1), "\"1\"");
+    let texts: Vec<_> = spans.children.iter().map(|&s| text(s)).collect();
+    assert_eq!(texts, ["<b y = 'é' z='2'/>", "text &amp; more\n"]);
+
+    let Xml::Element(b, None) = &children[0] else {
+        panic!("expected an empty element")
+    };
+    let spans = b.spans.as_deref().unwrap();
+    assert_eq!(spans.closing, None);
+    assert_eq!(spans.element(), spans.opening);
+    let (name, value) = spans.attributes[0];
+    assert_eq!((text(name), text(value)), ("y", "'é'"));
+    let at = |offset, line, column| Position {
+        offset,
+        line,
+        column,
+    };
+    assert_eq!((value.start, value.end), (at(41, 3, 10), at(45, 3, 13)));
+    assert_eq!(spans.attributes[1].0.start, at(46, 3, 14));
+
+    let document = Document::parse_with(data, options).unwrap();
+    assert_eq!(document.root, root);
+    let tree = XmlRef::from_input_str_with(data, options).unwrap();
+    let XmlRef::Element(tag, _) = &tree else {
+        panic!("expected an element")
+    };
+    assert_eq!(tag.spans.as_deref(), a.spans.as_deref());
+
+    let starts: Vec<_> = Reader::with_options(data, options)
+        .filter_map(|e| match e.unwrap() {
+            EventRef::StartElement(t) => t.spans.map(|s| text(s.opening)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(starts, ["<a x=\"1\">", "<b y = 'é' z='2'/>"]);
+
+    let data = "<a>\n  x  <b/>\n  <c> y </c >\n</a>";
+    let text = |span: Span| &data[span.range()];
+    let root = Xml::from_input_str_with(data, options).unwrap();
+    let Xml::Element(a, _) = &root else {
+        panic!("expected an element")
+    };
+    let spans = a.spans.as_deref().unwrap();
+    let children: Vec<_> = spans.children.iter().map(|&s| text(s)).collect();
+    assert_eq!(children, ["\n  x  ", "<b/>", "<c> y </c >"]);
+
+    let spans: Vec<_> = Reader::with_options(data, options)
+        .filter_map(|e| match e.unwrap() {
+            EventRef::Text(_, span) | EventRef::EndElement(_, span) => Some(span.map(text)),
+            _ => None,
+        })
+        .collect();
+    let expected = [Some("\n  x  "), None, Some(" y "), Some("</c >"), Some("</a>")];
+    assert_eq!(spans, expected);
+    let input = BufReader::with_capacity(3, data.as_bytes());
+    let streamed: Vec<_> = StreamReader::with_options(input, options)
+        .filter_map(|e| match e.unwrap() {
+            Event::Text(_, span) | Event::EndElement(_, span) => Some(span.map(text)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(streamed, expected);
+
+    let root = Xml::from_input_str(data).unwrap();
+    let Xml::Element(a, _) = &root else {
+        panic!("expected an element")
+    };
+    assert_eq!(a.spans, None);
+}
+
+#[test]
+fn stops_streaming_at_errors() {
+    use crate::parse::{Reader, StreamReader};
+    use std::io::{BufReader, Cursor};
+
+    let data = "<!DOCTYPE a [\n  <!ENTITY e 'a long replacement text'>\n]>\n\
+                <a><!-- a comment that is longer than a read -->\
+                <?target data that is longer than a read?>&e;</a>";
+    let events: Vec<Event> = Reader::new(data).map(|e| e.unwrap().into()).collect();
+    for capacity in [1, 3, 7] {
+        let streamed = StreamReader::new(BufReader::with_capacity(capacity, data.as_bytes()))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(streamed, events);
+    }
+
+    let malformed = "<a><b c=d>".to_string() + &"x".repeat(1 << 20);
+    let mut input = Cursor::new(malformed.as_bytes());
+    let e = StreamReader::new(BufReader::with_capacity(64, &mut input))
+        .find_map(Result::err)
+        .unwrap();
+    assert_eq!((e.line(), e.column()), (1, 9));
+    assert!(input.position() <= 64);
+
+    // Parsed again each time more input is read, which must not take quadratic time.
+    let text = "x".repeat(1 << 20);
+    let data = format!("<a>{text}</a>");
+    let mut events = StreamReader::new(BufReader::with_capacity(64, data.as_bytes()));
+    assert!(matches!(events.nth(1), Some(Ok(Event::Text(t, _))) if t == text));
+}
@@ -1,20 +1,140 @@
+use nom::combinator::all_consuming;
+use std::{borrow::Cow, fmt, fs, io::Read, ops::Range, path::Path};
+
+use crate::{
+    encoding::decode,
+    error::{ErrorTrace, Position, XmlError, XmlErrorKind},
+    namespace::NamespaceError,
+    parse::ParseOptions,
+};
+
+/// An element name, `prefix:local` or just `local`.
+///
+/// `namespace_uri` is only filled in by `Xml::resolve_namespaces`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct QName {
+    pub prefix: Option<String>,
+    pub local: String,
+    pub namespace_uri: Option<String>,
+}
+
+impl From<&str> for QName {
+    fn from(name: &str) -> Self {
+        let (prefix, local) = match name.split_once(':') {
+            Some((prefix, local)) => (Some(prefix.into()), local.into()),
+            None => (None, name.into()),
+        };
+        QName {
+            prefix,
+            local,
+            namespace_uri: None,
+        }
+    }
+}
+
+impl From<String> for QName {
+    fn from(name: String) -> Self {
+        name.as_str().into()
+    }
+}
+
+impl fmt::Display for QName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.prefix {
+            Some(prefix) => write!(f, "{prefix}:{}", self.local),
+            None => f.write_str(&self.local),
+        }
+    }
+}
+
+// NOTE:
+// Compares against the name as written in the document, ignoring `namespace_uri`.
+impl PartialEq<str> for QName {
+    fn eq(&self, name: &str) -> bool {
+        qualified_eq(self.prefix.as_deref(), &self.local, name)
+    }
+}
+
+impl PartialEq<&str> for QName {
+    fn eq(&self, name: &&str) -> bool {
+        self == *name
+    }
+}
+
+fn qualified_eq(prefix: Option<&str>, local: &str, name: &str) -> bool {
+    match prefix {
+        Some(prefix) => name
+            .strip_prefix(prefix)
+            .and_then(|n| n.strip_prefix(':'))
+            .is_some_and(|n| n == local),
+        None => name == local,
+    }
+}
+
+/// Attributes of a tag, in the order they were written or inserted in.
 #[cfg(feature = "secure")]
-use std::collections::HashMap;
+pub type AttributeMap<K, V> = indexmap::IndexMap<K, V>;
+/// Attributes of a tag, in the order they were written or inserted in.
 #[cfg(feature = "fast")]
-use foldhash::HashMap;
+pub type AttributeMap<K, V> = indexmap::IndexMap<K, V, foldhash::fast::RandomState>;
+
+/// Where a piece of the input starts and ends.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+}
 
-use nom::error::ErrorKind;
+impl Span {
+    /// The byte range of the input that the span covers.
+    pub fn range(&self) -> Range<usize> {
+        self.start.offset..self.end.offset
+    }
+}
 
+/// Where the parts of an element are in the input, see `ParseOptions::spans`.
 #[derive(Clone, Debug, PartialEq, Eq)]
-pub struct Tag {
-    pub value: String,
-    pub attributes: HashMap<String, String>,
+pub struct TagSpans {
+    pub opening: Span,
+    /// `None` for an empty-element tag like `<a/>`, and from `parse::Reader` and
+    /// `parse::StreamReader`, which report closing tags in their `EndElement` events.
+    pub closing: Option<Span>,
+    /// The name and value, quotes included, of each attribute, in the order of
+    /// `attributes`.
+    pub attributes: Vec<(Span, Span)>,
+    /// Where each child is as written, so text includes the whitespace that was trimmed
+    /// from it. Empty from the readers, which report it in their `Text` events.
+    pub children: Vec<Span>,
+}
+
+impl TagSpans {
+    /// From the start of the opening tag to the end of the closing tag.
+    pub fn element(&self) -> Span {
+        Span {
+            start: self.opening.start,
+            end: self.closing.unwrap_or(self.opening).end,
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Tag {
+    pub value: QName,
+    pub attributes: AttributeMap<String, String>,
+    /// Only recorded with `ParseOptions::spans`.
+    pub spans: Option<Box<TagSpans>>,
+}
+
+/// Dropping, cloning, comparing and serializing do not recurse, so documents of any
+/// depth are safe to handle. The price is a `Drop` impl, which means an `Xml` cannot be
+/// destructured by move: match on `&mut Xml` and `mem::take` the parts instead.
+#[derive(Debug)]
 pub enum Xml {
     Element(Tag, Option<Vec<Xml>>),
     Text(String),
+    CData(String),
+    Comment(String),
+    ProcessingInstruction { target: String, data: String },
 }
 
 impl Xml {
@@ -22,15 +142,18 @@ impl Xml {
         Xml::Element(t, None)
     }
 
-    pub fn from_input_str<'a>(i: &'a str) -> Result<Self, nom::Err<(&'a str, ErrorKind)>> {
-        crate::parse::root::<(&str, ErrorKind)>(i).map(|(_, x)| x)
+    pub fn from_input_str(i: &str) -> Result<Self, XmlError> {
+        Xml::from_input_str_with(i, ParseOptions::default())
+    }
+
+    pub fn from_input_str_with(i: &str, options: ParseOptions) -> Result<Self, XmlError> {
+        all_consuming(crate::parse::root_with::<ErrorTrace>(options))(i)
+            .map(|(_, x)| x)
+            .map_err(|e| XmlError::from_nom(i, e))
     }
 
     pub fn is_element(&self) -> bool {
-        match self {
-            Xml::Element(_, _) => true,
-            Xml::Text(_) => false,
-        }
+        matches!(self, Xml::Element(_, _))
     }
 
     pub fn tag_has_name(&self, name: &str) -> bool {
@@ -40,25 +163,210 @@ impl Xml {
         }
     }
 
-    // TODO:
-    // from_bytes, from_file, etc.
+    /// Only meaningful after `resolve_namespaces`.
+    pub fn tag_has_name_ns(&self, namespace_uri: &str, local: &str) -> bool {
+        match self {
+            Xml::Element(t, _) => {
+                t.value.local == local && t.value.namespace_uri.as_deref() == Some(namespace_uri)
+            }
+            _ => false,
+        }
+    }
+
+    /// Fills in `QName::namespace_uri` on every element from the `xmlns` declarations in scope.
+    pub fn resolve_namespaces(self) -> Result<Self, NamespaceError> {
+        crate::namespace::resolve(self)
+    }
+
+    /// Parses `bytes` in the encoding given by their byte order mark or XML declaration,
+    /// see `encoding::decode`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, XmlError> {
+        Xml::from_input_str(&decode(bytes)?)
+    }
+
+    pub fn from_reader(reader: impl Read) -> Result<Self, XmlError> {
+        Xml::from_bytes(&read_all(reader)?)
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, XmlError> {
+        Xml::from_bytes(&fs::read(path).map_err(io_error)?)
+    }
+}
+
+fn read_all(mut reader: impl Read) -> Result<Vec<u8>, XmlError> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes).map_err(io_error)?;
+    Ok(bytes)
+}
+
+fn io_error(e: std::io::Error) -> XmlError {
+    XmlError::new("", Position::START, XmlErrorKind::Io(e.to_string()))
+}
+
+// NOTE:
+// Dropping the children one level at a time would recurse as deep as the document goes,
+// so the descendants are flattened into a single `Vec` first.
+impl Drop for Xml {
+    fn drop(&mut self) {
+        if let Xml::Element(_, Some(children)) = self {
+            let mut descendants = std::mem::take(children);
+            while let Some(mut x) = descendants.pop() {
+                if let Xml::Element(_, Some(children)) = &mut x {
+                    descendants.append(children);
+                }
+            }
+        }
+    }
+}
+
+// NOTE:
+// Iterative for the same reason as `Drop`: each element is copied without its children
+// first, and the children are filled in afterwards.
+impl Clone for Xml {
+    fn clone(&self) -> Self {
+        let shallow = |x: &Xml| match x {
+            Xml::Element(t, children) => Xml::Element(
+                t.clone(),
+                children.as_ref().map(|c| Vec::with_capacity(c.len())),
+            ),
+            Xml::Text(s) => Xml::Text(s.clone()),
+            Xml::CData(s) => Xml::CData(s.clone()),
+            Xml::Comment(s) => Xml::Comment(s.clone()),
+            Xml::ProcessingInstruction { target, data } => Xml::ProcessingInstruction {
+                target: target.clone(),
+                data: data.clone(),
+            },
+        };
+
+        let mut copy = shallow(self);
+        // The children left to fill in, with the children they are copies of.
+        let mut pending = Vec::new();
+        if let (Xml::Element(_, Some(copies)), Xml::Element(_, Some(children))) = (&mut copy, self)
+        {
+            pending.push((copies, children));
+        }
+        while let Some((copies, children)) = pending.pop() {
+            copies.extend(children.iter().map(shallow));
+            for pair in copies.iter_mut().zip(children) {
+                if let (Xml::Element(_, Some(copies)), Xml::Element(_, Some(children))) = pair {
+                    pending.push((copies, children));
+                }
+            }
+        }
+
+        copy
+    }
+}
+
+impl PartialEq for Xml {
+    fn eq(&self, other: &Self) -> bool {
+        let mut pending = vec![(self, other)];
+        while let Some(pair) = pending.pop() {
+            let equal = match pair {
+                (Xml::Element(a, a_children), Xml::Element(b, b_children)) => {
+                    match (a_children, b_children) {
+                        (Some(a_children), Some(b_children))
+                            if a_children.len() == b_children.len() =>
+                        {
+                            pending.extend(a_children.iter().zip(b_children));
+                        }
+                        (None, None) => (),
+                        _ => return false,
+                    }
+                    a == b
+                }
+                (Xml::Text(a), Xml::Text(b))
+                | (Xml::CData(a), Xml::CData(b))
+                | (Xml::Comment(a), Xml::Comment(b)) => a == b,
+                (
+                    Xml::ProcessingInstruction { target, data },
+                    Xml::ProcessingInstruction {
+                        target: other_target,
+                        data: other_data,
+                    },
+                ) => target == other_target && data == other_data,
+                _ => false,
+            };
+            if !equal {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl Eq for Xml {}
+
+/// Borrowed counterpart of `QName`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct QNameRef<'a> {
+    pub prefix: Option<&'a str>,
+    pub local: &'a str,
+    pub namespace_uri: Option<Cow<'a, str>>,
+}
+
+impl<'a> From<&'a str> for QNameRef<'a> {
+    fn from(name: &'a str) -> Self {
+        let (prefix, local) = match name.split_once(':') {
+            Some((prefix, local)) => (Some(prefix), local),
+            None => (None, name),
+        };
+        QNameRef {
+            prefix,
+            local,
+            namespace_uri: None,
+        }
+    }
+}
+
+impl fmt::Display for QNameRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.prefix {
+            Some(prefix) => write!(f, "{prefix}:{}", self.local),
+            None => f.write_str(self.local),
+        }
+    }
+}
+
+impl PartialEq<str> for QNameRef<'_> {
+    fn eq(&self, name: &str) -> bool {
+        qualified_eq(self.prefix, self.local, name)
+    }
+}
+
+impl PartialEq<&str> for QNameRef<'_> {
+    fn eq(&self, name: &&str) -> bool {
+        self == *name
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct TagRef<'a> {
-    pub value: &'a str,
-    pub attributes: HashMap<&'a str, &'a str>,
+    pub value: QNameRef<'a>,
+    pub attributes: AttributeMap<&'a str, Cow<'a, str>>,
+    /// Only recorded with `ParseOptions::spans`.
+    pub spans: Option<Box<TagSpans>>,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+/// See `Xml`, this has the same `Drop` impl.
+#[derive(Debug)]
 pub enum XmlRef<'a> {
     Element(TagRef<'a>, Option<Vec<XmlRef<'a>>>),
-    Text(&'a str),
+    Text(Cow<'a, str>),
+    CData(&'a str),
+    Comment(&'a str),
+    ProcessingInstruction { target: &'a str, data: &'a str },
 }
 
 impl<'a> XmlRef<'a> {
-    pub fn from_input_str(i: &'a str) -> Result<Self, nom::Err<(&'a str, ErrorKind)>> {
-        crate::parse::root_ref::<(&str, ErrorKind)>(i).map(|(_, x)| x)
+    pub fn from_input_str(i: &'a str) -> Result<Self, XmlError> {
+        XmlRef::from_input_str_with(i, ParseOptions::default())
+    }
+
+    pub fn from_input_str_with(i: &'a str, options: ParseOptions) -> Result<Self, XmlError> {
+        all_consuming(crate::parse::root_ref_with::<ErrorTrace>(options))(i)
+            .map(|(_, x)| x)
+            .map_err(|e| XmlError::from_nom(i, e))
     }
 
     pub fn from_tag(t: TagRef<'a>) -> Self {
@@ -66,10 +374,7 @@ impl<'a> XmlRef<'a> {
     }
 
     pub fn is_element(&self) -> bool {
-        match self {
-            XmlRef::Element(_, _) => true,
-            XmlRef::Text(_) => false,
-        }
+        matches!(self, XmlRef::Element(_, _))
     }
 
     pub fn tag_has_name(&self, name: &str) -> bool {
@@ -78,6 +383,702 @@ impl<'a> XmlRef<'a> {
             _ => false,
         }
     }
+
+    /// Only meaningful after `resolve_namespaces`.
+    pub fn tag_has_name_ns(&self, namespace_uri: &str, local: &str) -> bool {
+        match self {
+            XmlRef::Element(t, _) => {
+                t.value.local == local && t.value.namespace_uri.as_deref() == Some(namespace_uri)
+            }
+            _ => false,
+        }
+    }
+
+    /// Fills in `QNameRef::namespace_uri` on every element from the `xmlns` declarations in scope.
+    pub fn resolve_namespaces(self) -> Result<Self, NamespaceError> {
+        crate::namespace::resolve_ref(self)
+    }
+}
+
+impl Drop for XmlRef<'_> {
+    fn drop(&mut self) {
+        if let XmlRef::Element(_, Some(children)) = self {
+            let mut descendants = std::mem::take(children);
+            while let Some(mut x) = descendants.pop() {
+                if let XmlRef::Element(_, Some(children)) = &mut x {
+                    descendants.append(children);
+                }
+            }
+        }
+    }
+}
+
+// NOTE:
+// See `Clone for Xml`.
+impl Clone for XmlRef<'_> {
+    fn clone(&self) -> Self {
+        let shallow = |x: &Self| match x {
+            XmlRef::Element(t, children) => XmlRef::Element(
+                t.clone(),
+                children.as_ref().map(|c| Vec::with_capacity(c.len())),
+            ),
+            XmlRef::Text(s) => XmlRef::Text(s.clone()),
+            XmlRef::CData(s) => XmlRef::CData(s),
+            XmlRef::Comment(s) => XmlRef::Comment(s),
+            XmlRef::ProcessingInstruction { target, data } => {
+                XmlRef::ProcessingInstruction { target, data }
+            }
+        };
+
+        let mut copy = shallow(self);
+        let mut pending = Vec::new();
+        if let (XmlRef::Element(_, Some(copies)), XmlRef::Element(_, Some(children))) =
+            (&mut copy, self)
+        {
+            pending.push((copies, children));
+        }
+        while let Some((copies, children)) = pending.pop() {
+            copies.extend(children.iter().map(shallow));
+            for pair in copies.iter_mut().zip(children) {
+                if let (XmlRef::Element(_, Some(copies)), XmlRef::Element(_, Some(children))) = pair
+                {
+                    pending.push((copies, children));
+                }
+            }
+        }
+
+        copy
+    }
+}
+
+impl PartialEq for XmlRef<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        let mut pending = vec![(self, other)];
+        while let Some(pair) = pending.pop() {
+            let equal = match pair {
+                (XmlRef::Element(a, a_children), XmlRef::Element(b, b_children)) => {
+                    match (a_children, b_children) {
+                        (Some(a_children), Some(b_children))
+                            if a_children.len() == b_children.len() =>
+                        {
+                            pending.extend(a_children.iter().zip(b_children));
+                        }
+                        (None, None) => (),
+                        _ => return false,
+                    }
+                    a == b
+                }
+                (XmlRef::Text(a), XmlRef::Text(b)) => a == b,
+                (XmlRef::CData(a), XmlRef::CData(b)) | (XmlRef::Comment(a), XmlRef::Comment(b)) => {
+                    a == b
+                }
+                (
+                    XmlRef::ProcessingInstruction { target, data },
+                    XmlRef::ProcessingInstruction {
+                        target: other_target,
+                        data: other_data,
+                    },
+                ) => target == other_target && data == other_data,
+                _ => false,
+            };
+            if !equal {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl Eq for XmlRef<'_> {}
+
+/// The `<?xml ... ?>` declaration at the start of a document.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct XmlDeclaration {
+    pub version: String,
+    pub encoding: Option<String>,
+    pub standalone: Option<bool>,
+}
+
+impl Default for XmlDeclaration {
+    fn default() -> Self {
+        XmlDeclaration {
+            version: "1.0".into(),
+            encoding: None,
+            standalone: None,
+        }
+    }
+}
+
+/// `SYSTEM "uri"` or `PUBLIC "id" "uri"`. The system literal may only be missing from
+/// notation declarations.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ExternalId {
+    System(String),
+    Public {
+        public_id: String,
+        system_id: Option<String>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Repetition {
+    One,
+    /// `?`
+    Optional,
+    /// `*`
+    ZeroOrMore,
+    /// `+`
+    OneOrMore,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Particle {
+    Name(String),
+    /// `(a | b)`
+    Choice(Vec<ContentParticle>),
+    /// `(a, b)`
+    Sequence(Vec<ContentParticle>),
+}
+
+impl Particle {
+    /// The particles of a group, none for a name.
+    pub(crate) fn particles(&self) -> &[ContentParticle] {
+        match self {
+            Particle::Name(_) => &[],
+            Particle::Choice(particles) | Particle::Sequence(particles) => particles,
+        }
+    }
+}
+
+/// Dropping, cloning and comparing do not recurse, like for `Xml`, so groups may nest as
+/// deep as `ParseOptions::max_depth` allows.
+#[derive(Debug)]
+pub struct ContentParticle {
+    pub particle: Particle,
+    pub repetition: Repetition,
+}
+
+impl Drop for ContentParticle {
+    fn drop(&mut self) {
+        let (Particle::Choice(particles) | Particle::Sequence(particles)) = &mut self.particle
+        else {
+            return;
+        };
+        let mut descendants = std::mem::take(particles);
+        while let Some(mut cp) = descendants.pop() {
+            if let Particle::Choice(particles) | Particle::Sequence(particles) = &mut cp.particle {
+                descendants.append(particles);
+            }
+        }
+    }
+}
+
+// NOTE:
+// See `Clone for Xml`.
+impl Clone for ContentParticle {
+    fn clone(&self) -> Self {
+        let shallow = |cp: &ContentParticle| ContentParticle {
+            particle: match &cp.particle {
+                Particle::Name(name) => Particle::Name(name.clone()),
+                Particle::Choice(particles) => {
+                    Particle::Choice(Vec::with_capacity(particles.len()))
+                }
+                Particle::Sequence(particles) => {
+                    Particle::Sequence(Vec::with_capacity(particles.len()))
+                }
+            },
+            repetition: cp.repetition,
+        };
+
+        let mut copy = shallow(self);
+        // The particles left to fill in, with the particles they are copies of.
+        let mut pending = Vec::new();
+        if let Particle::Choice(copies) | Particle::Sequence(copies) = &mut copy.particle {
+            pending.push((copies, self.particle.particles()));
+        }
+        while let Some((copies, particles)) = pending.pop() {
+            copies.extend(particles.iter().map(shallow));
+            for (copy, particle) in copies.iter_mut().zip(particles) {
+                if let Particle::Choice(copies) | Particle::Sequence(copies) = &mut copy.particle {
+                    pending.push((copies, particle.particle.particles()));
+                }
+            }
+        }
+
+        copy
+    }
+}
+
+impl PartialEq for ContentParticle {
+    fn eq(&self, other: &Self) -> bool {
+        let mut pending = vec![(self, other)];
+        while let Some((a, b)) = pending.pop() {
+            let equal = a.repetition == b.repetition
+                && match (&a.particle, &b.particle) {
+                    (Particle::Name(a), Particle::Name(b)) => a == b,
+                    (Particle::Choice(a), Particle::Choice(b))
+                    | (Particle::Sequence(a), Particle::Sequence(b)) => a.len() == b.len(),
+                    _ => false,
+                };
+            if !equal {
+                return false;
+            }
+            pending.extend(a.particle.particles().iter().zip(b.particle.particles()));
+        }
+        true
+    }
+}
+
+impl Eq for ContentParticle {}
+
+/// What an `<!ELEMENT>` declaration allows inside the element.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ContentSpec {
+    Empty,
+    Any,
+    /// `(#PCDATA | a | b)*`, holding the element names.
+    Mixed(Vec<String>),
+    Children(ContentParticle),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AttributeType {
+    CData,
+    Id,
+    IdRef,
+    IdRefs,
+    Entity,
+    Entities,
+    NmToken,
+    NmTokens,
+    Notation(Vec<String>),
+    Enumeration(Vec<String>),
+}
+
+/// Default values are kept as written in the DTD, references included.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DefaultDecl {
+    Required,
+    Implied,
+    Fixed(String),
+    Value(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AttributeDef {
+    pub name: String,
+    pub attribute_type: AttributeType,
+    pub default: DefaultDecl,
+}
+
+/// Replacement texts are kept as written in the DTD, references included.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EntityValue {
+    Internal(String),
+    External {
+        id: ExternalId,
+        notation: Option<String>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MarkupDecl {
+    Element {
+        name: String,
+        content: ContentSpec,
+    },
+    AttList {
+        element: String,
+        attributes: Vec<AttributeDef>,
+    },
+    /// `parameter` entities are the `<!ENTITY % name ...>` ones.
+    Entity {
+        name: String,
+        parameter: bool,
+        value: EntityValue,
+    },
+    Notation {
+        name: String,
+        id: ExternalId,
+    },
+    /// `%name;` between declarations.
+    ParameterEntityReference(String),
+    Comment(String),
+    ProcessingInstruction {
+        target: String,
+        data: String,
+    },
+}
+
+/// The `<!DOCTYPE ...>` declaration, with the declarations of its internal subset.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Doctype {
+    pub name: String,
+    pub external_id: Option<ExternalId>,
+    pub internal_subset: Vec<MarkupDecl>,
+}
+
+impl Doctype {
+    /// Declarations of the attributes of `element`, across all of its `<!ATTLIST>`s.
+    pub fn attributes_of<'d>(&'d self, element: &'d str) -> impl Iterator<Item = &'d AttributeDef> {
+        self.internal_subset
+            .iter()
+            .flat_map(move |decl| match decl {
+                MarkupDecl::AttList {
+                    element: e,
+                    attributes,
+                } if e == element => attributes.as_slice(),
+                _ => &[],
+            })
+    }
+
+    /// The general (i.e. not parameter) entity called `name`, the first declaration winning.
+    pub fn entity(&self, name: &str) -> Option<&EntityValue> {
+        self.internal_subset.iter().find_map(|decl| match decl {
+            MarkupDecl::Entity {
+                name: n,
+                parameter: false,
+                value,
+            } if n == name => Some(value),
+            _ => None,
+        })
+    }
+}
+
+/// Comments and processing instructions outside of the root element.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Misc {
+    Comment(String),
+    ProcessingInstruction { target: String, data: String },
+}
+
+/// A whole document: the root element along with what surrounds it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Document {
+    pub declaration: Option<XmlDeclaration>,
+    /// Comments and processing instructions between the declaration and the `doctype`.
+    pub before_doctype: Vec<Misc>,
+    pub doctype: Option<Doctype>,
+    /// Comments and processing instructions after the `doctype`, or all of them when there
+    /// is none.
+    pub prolog: Vec<Misc>,
+    pub root: Xml,
+    pub epilog: Vec<Misc>,
+}
+
+impl Document {
+    pub fn parse(i: &str) -> Result<Self, XmlError> {
+        Document::parse_with(i, ParseOptions::default())
+    }
+
+    pub fn parse_with(i: &str, options: ParseOptions) -> Result<Self, XmlError> {
+        crate::parse::document_with::<ErrorTrace>(options)(i)
+            .map(|(_, d)| d)
+            .map_err(|e| XmlError::from_nom(i, e))
+    }
+
+    /// Parses `bytes` in the encoding given by their byte order mark or XML declaration,
+    /// see `encoding::decode`.
+    ///
+    /// The document is held as UTF-8 from then on, so an encoding in the declaration is
+    /// replaced by `UTF-8` when the input was in another one.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, XmlError> {
+        let decoded = decode(bytes)?;
+        let mut document = Document::parse(&decoded)?;
+        let declared = document.declaration.as_mut().and_then(|d| d.encoding.as_mut());
+        if let (Cow::Owned(_), Some(encoding)) = (decoded, declared) {
+            *encoding = "UTF-8".into();
+        }
+        Ok(document)
+    }
+
+    pub fn from_reader(reader: impl Read) -> Result<Self, XmlError> {
+        Document::from_bytes(&read_all(reader)?)
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, XmlError> {
+        Document::from_bytes(&fs::read(path).map_err(io_error)?)
+    }
+}
+
+/// A document that keeps every byte of its source, so that `serialize::cst_to_string`
+/// gives the input back unchanged, edits aside.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CstDocument {
+    /// Everything before the root element as written: declaration, DOCTYPE, comments,
+    /// processing instructions and whitespace.
+    pub prolog: String,
+    pub root: CstElement,
+    /// Everything after the root element as written.
+    pub epilog: String,
+}
+
+impl CstDocument {
+    pub fn parse(i: &str) -> Result<Self, XmlError> {
+        CstDocument::parse_with(i, ParseOptions::default())
+    }
+
+    // NOTE:
+    // The document is parsed as a `Document` first, so that it gets the same checks; the
+    // lossless parser itself relies on the input being well-formed.
+    pub fn parse_with(i: &str, options: ParseOptions) -> Result<Self, XmlError> {
+        Document::parse_with(i, options)?;
+        all_consuming(crate::parse::cst_document::<ErrorTrace>)(i)
+            .map(|(_, d)| d)
+            .map_err(|e| XmlError::from_nom(i, e))
+    }
+}
+
+/// Element of a `CstDocument`.
+///
+/// Dropping, cloning and comparing do not recurse, see `Xml`.
+#[derive(Debug, Default)]
+pub struct CstElement {
+    pub name: String,
+    pub attributes: Vec<CstAttribute>,
+    /// Whitespace between the attributes and the `>` or `/>` of the opening tag.
+    pub opening_space: String,
+    /// `None` for an empty-element tag like `<a/>`, as opposed to `<a></a>`.
+    pub children: Option<Vec<CstNode>>,
+    /// Whitespace between the name and the `>` of the closing tag.
+    pub closing_space: String,
+}
+
+impl CstElement {
+    pub fn attribute(&self, name: &str) -> Option<&CstAttribute> {
+        self.attributes.iter().find(|a| a.name == name)
+    }
+
+    /// Changes the value of the attribute `name`, keeping how it is written otherwise,
+    /// or adds it after the others.
+    pub fn set_attribute(&mut self, name: &str, value: &str) {
+        match self.attributes.iter_mut().find(|a| a.name == name) {
+            Some(a) => a.set_value(value),
+            None => {
+                let mut a = CstAttribute {
+                    leading_space: " ".into(),
+                    name: name.into(),
+                    equals: "=".into(),
+                    quote: '"',
+                    raw_value: String::new(),
+                };
+                a.set_value(value);
+                self.attributes.push(a);
+            }
+        }
+    }
+
+    pub fn remove_attribute(&mut self, name: &str) -> Option<CstAttribute> {
+        let n = self.attributes.iter().position(|a| a.name == name)?;
+        Some(self.attributes.remove(n))
+    }
+
+    /// The child elements, skipping text and other markup.
+    pub fn elements(&self) -> impl Iterator<Item = &CstElement> {
+        self.children.iter().flatten().filter_map(|c| match c {
+            CstNode::Element(e) => Some(e),
+            _ => None,
+        })
+    }
+
+    pub fn elements_mut(&mut self) -> impl Iterator<Item = &mut CstElement> {
+        self.children.iter_mut().flatten().filter_map(|c| match c {
+            CstNode::Element(e) => Some(e),
+            _ => None,
+        })
+    }
+}
+
+// NOTE:
+// See `Drop for Xml`.
+impl Drop for CstElement {
+    fn drop(&mut self) {
+        let mut descendants = self.children.take().unwrap_or_default();
+        while let Some(mut node) = descendants.pop() {
+            if let CstNode::Element(e) = &mut node {
+                descendants.extend(e.children.take().into_iter().flatten());
+            }
+        }
+    }
+}
+
+// NOTE:
+// See `Clone for Xml`.
+impl Clone for CstElement {
+    fn clone(&self) -> Self {
+        let shallow = |e: &CstElement| CstElement {
+            name: e.name.clone(),
+            attributes: e.attributes.clone(),
+            opening_space: e.opening_space.clone(),
+            children: e.children.as_ref().map(|c| Vec::with_capacity(c.len())),
+            closing_space: e.closing_space.clone(),
+        };
+        let shallow_node = |node: &CstNode| match node {
+            CstNode::Element(e) => CstNode::Element(shallow(e)),
+            CstNode::Text(s) => CstNode::Text(s.clone()),
+            CstNode::Markup(s) => CstNode::Markup(s.clone()),
+        };
+
+        let mut copy = shallow(self);
+        // The children left to fill in, with the children they are copies of.
+        let mut pending = Vec::new();
+        if let (Some(copies), Some(children)) = (&mut copy.children, &self.children) {
+            pending.push((copies, children));
+        }
+        while let Some((copies, children)) = pending.pop() {
+            copies.extend(children.iter().map(shallow_node));
+            for pair in copies.iter_mut().zip(children) {
+                if let (CstNode::Element(copy), CstNode::Element(e)) = pair {
+                    if let (Some(copies), Some(children)) = (&mut copy.children, &e.children) {
+                        pending.push((copies, children));
+                    }
+                }
+            }
+        }
+
+        copy
+    }
+}
+
+impl PartialEq for CstElement {
+    fn eq(&self, other: &Self) -> bool {
+        let mut pending = vec![(self, other)];
+        while let Some((a, b)) = pending.pop() {
+            let equal = a.name == b.name
+                && a.attributes == b.attributes
+                && a.opening_space == b.opening_space
+                && a.closing_space == b.closing_space;
+            if !equal {
+                return false;
+            }
+            match (&a.children, &b.children) {
+                (Some(a), Some(b)) if a.len() == b.len() => {
+                    for pair in a.iter().zip(b) {
+                        match pair {
+                            (CstNode::Element(a), CstNode::Element(b)) => pending.push((a, b)),
+                            (CstNode::Text(a), CstNode::Text(b))
+                            | (CstNode::Markup(a), CstNode::Markup(b))
+                                if a == b => {}
+                            _ => return false,
+                        }
+                    }
+                }
+                (None, None) => (),
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+impl Eq for CstElement {}
+
+/// Attribute of a `CstElement`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CstAttribute {
+    /// Whitespace between the previous attribute, or the element name, and this one.
+    pub leading_space: String,
+    pub name: String,
+    /// The `=` along with the whitespace around it.
+    pub equals: String,
+    /// `"` or `'`.
+    pub quote: char,
+    /// The value between the quotes as written, references included.
+    pub raw_value: String,
+}
+
+impl CstAttribute {
+    /// Replaces the value, escaping it as needed.
+    pub fn set_value(&mut self, value: &str) {
+        self.raw_value = crate::serialize::escape_attribute(value).into_owned();
+    }
+}
+
+/// Content of a `CstElement`. Everything but elements is kept as written, references
+/// included.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CstNode {
+    Element(CstElement),
+    Text(String),
+    /// A comment, CDATA section or processing instruction, delimiters included.
+    Markup(String),
+}
+
+/// Items produced by `parse::StreamReader`, see `EventRef`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    Declaration(XmlDeclaration),
+    DocType(Doctype),
+    StartElement(Tag),
+    EndElement(QName, Option<Span>),
+    Text(String, Option<Span>),
+    CData(String),
+    Comment(String),
+    ProcessingInstruction { target: String, data: String },
+}
+
+/// Items produced by `parse::Reader`.
+///
+/// Self-closing elements produce a `StartElement` immediately followed by an `EndElement`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EventRef<'a> {
+    Declaration(XmlDeclaration),
+    DocType(Doctype),
+    StartElement(TagRef<'a>),
+    /// With the span of the closing tag, see `ParseOptions::spans`. There is none after an
+    /// empty-element tag like `<a/>`.
+    EndElement(QNameRef<'a>, Option<Span>),
+    /// With the span of the text as written, so including the whitespace that was trimmed
+    /// from it.
+    Text(Cow<'a, str>, Option<Span>),
+    CData(&'a str),
+    Comment(&'a str),
+    ProcessingInstruction {
+        target: &'a str,
+        data: &'a str,
+    },
+}
+
+impl From<QNameRef<'_>> for QName {
+    fn from(q: QNameRef<'_>) -> Self {
+        QName {
+            prefix: q.prefix.map(String::from),
+            local: q.local.into(),
+            namespace_uri: q.namespace_uri.map(Cow::into_owned),
+        }
+    }
+}
+
+impl From<TagRef<'_>> for Tag {
+    fn from(t: TagRef<'_>) -> Self {
+        Tag {
+            value: t.value.into(),
+            attributes: t
+                .attributes
+                .into_iter()
+                .map(|(k, v)| (k.into(), v.into_owned()))
+                .collect(),
+            spans: t.spans,
+        }
+    }
+}
+
+impl From<EventRef<'_>> for Event {
+    fn from(e: EventRef<'_>) -> Self {
+        match e {
+            EventRef::Declaration(d) => Event::Declaration(d),
+            EventRef::DocType(d) => Event::DocType(d),
+            EventRef::StartElement(t) => Event::StartElement(t.into()),
+            EventRef::EndElement(n, span) => Event::EndElement(n.into(), span),
+            EventRef::Text(t, span) => Event::Text(t.into_owned(), span),
+            EventRef::CData(c) => Event::CData(c.into()),
+            EventRef::Comment(c) => Event::Comment(c.into()),
+            EventRef::ProcessingInstruction { target, data } => Event::ProcessingInstruction {
+                target: target.into(),
+                data: data.into(),
+            },
+        }
+    }
 }
 
 // TODO: